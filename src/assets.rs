@@ -1,17 +1,29 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 
 use ggez::{audio::SoundData, graphics, Context, GameResult};
 use glam::{vec2, Vec2};
-use rand::{prelude::StdRng, SeedableRng};
+use rand::SeedableRng;
 
-use crate::scoring::{load_high_scores_table, HighScore, HighScoresTable, HIGH_SCORE_PATH};
+use crate::{
+    scoring::{load_high_scores_table, HighScore, HighScoresTable, HIGH_SCORE_PATH},
+    simulation::GameRng,
+    the_rules::{load_rules, GameRules},
+};
 
 pub struct Assets {
     pub font: graphics::Font,
     pub block_image: graphics::Image,
     pub high_score_table: RefCell<HighScoresTable<HighScore>>,
-    pub rng: RefCell<StdRng>,
+    /// for cosmetics only, reseeded from each game's seed
+    pub effects_rng: RefCell<GameRng>,
     pub tile_sz: Vec2,
+    pub sound_on: Cell<bool>,
+    /// the rulesets in `rules/` that could be loaded, never empty
+    pub rule_sets: Vec<GameRules>,
+    /// load errors for rulesets and puzzles that failed to parse or validate
+    pub rule_errors: Vec<String>,
+    /// the puzzles in `puzzles/` that could be loaded
+    pub puzzles: Vec<GameRules>,
     pub game_over_sound: ggez::audio::SoundData,
     pub thud_sound: ggez::audio::SoundData,
     pub score_sound: ggez::audio::SoundData,
@@ -19,14 +31,24 @@ pub struct Assets {
 
 impl Assets {
     pub fn load(ctx: &mut Context) -> GameResult<Self> {
+        let (mut rule_sets, mut rule_errors) = load_rules_dir(ctx, "/rules");
+        if rule_sets.is_empty() {
+            rule_sets.push(GameRules::default());
+        }
+        let (puzzles, puzzle_errors) = load_rules_dir(ctx, "/puzzles");
+        rule_errors.extend(puzzle_errors);
         let high_scores =
             load_high_scores_table(HIGH_SCORE_PATH).unwrap_or_else(|_| HighScoresTable::new());
         Ok(Self {
             font: graphics::Font::new(ctx, "/ProFontWindows.ttf")?,
             block_image: graphics::Image::new(ctx, "/block_2.png")?,
             high_score_table: RefCell::new(high_scores),
-            rng: RefCell::new(StdRng::from_entropy()),
+            effects_rng: RefCell::new(GameRng::from_entropy()),
             tile_sz: vec2(32.0, 32.0),
+            sound_on: Cell::new(true),
+            rule_sets,
+            rule_errors,
+            puzzles,
             game_over_sound: ggez::audio::SoundData::new(
                 ctx,
                 "/mixkit-player-losing-or-failing-2042.wav",
@@ -36,3 +58,27 @@ impl Assets {
         })
     }
 }
+
+/// Every ruleset in a resource directory in file name order,
+/// and why the ones that didn't load failed.
+fn load_rules_dir(ctx: &mut Context, dir: &str) -> (Vec<GameRules>, Vec<String>) {
+    let mut paths: Vec<_> = ggez::filesystem::read_dir(ctx, dir)
+        .map(|paths| paths.collect())
+        .unwrap_or_default();
+    paths.sort();
+    let mut rule_sets = vec![];
+    let mut rule_errors = vec![];
+    for path in paths {
+        let rules = ggez::filesystem::open(ctx, &path)
+            .map_err(|e| e.into())
+            .and_then(load_rules);
+        match rules {
+            Ok(rules) => rule_sets.push(rules),
+            Err(e) => {
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                rule_errors.push(format!("{}: {}", file_name, e));
+            }
+        }
+    }
+    (rule_sets, rule_errors)
+}
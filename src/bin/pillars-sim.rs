@@ -0,0 +1,31 @@
+use std::{env, fs::File, process};
+
+use pillars_ggez::{
+    the_rules::load_rules,
+    tournament::{self, parse_args},
+};
+
+/// Play bot games for each ruleset given without a window, and print how they went.
+fn main() {
+    let tournament = parse_args(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let mut rule_sets = vec![];
+    for path in tournament.rules_paths.iter() {
+        let rules = File::open(path).map_err(|e| e.into()).and_then(load_rules);
+        match rules {
+            Ok(rules) => rule_sets.push(rules),
+            Err(e) => {
+                eprintln!("couldn't load {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    let summaries = tournament::run(&tournament, &rule_sets);
+    if tournament.csv {
+        print!("{}", tournament::csv(&summaries));
+    } else {
+        print!("{}", tournament::table(&summaries));
+    }
+}
@@ -1,18 +1,28 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    str::FromStr,
+};
+
 use ggez::graphics::Color;
 use glam::{vec2, Vec2};
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
 use crate::{
     drawing::rgb,
-    game_loop::PilPos,
-    helpful_things::{neighbours, search},
+    grid::Grid,
+    helpful_things::search,
     pillar::Pillar,
+    simulation::PilPos,
+    the_rules::{GameRules, MatchRule},
 };
 
-#[derive(Clone, Copy, Debug, Eq, FromPrimitive, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, FromPrimitive, Hash, PartialEq, Serialize)]
 pub enum Block {
     Red = 0,
     Green = 1,
@@ -24,9 +34,14 @@ pub enum Block {
     Violet = 7,
     White = 8,
     Black = 9,
+    /// pushed up by a rising floor, never matches
+    Garbage = 10,
 }
 
 impl Block {
+    /// How many colors there are, garbage left out.
+    pub const COLORS: usize = Self::Garbage as usize;
+
     pub fn color(self) -> Color {
         match self {
             Self::Red => Color::from_rgb_u32(0xFF6961),
@@ -39,11 +54,39 @@ impl Block {
             Self::Violet => rgb(0.5, 0.1, 0.9),
             Self::White => Color::WHITE,
             Self::Black => rgb(0.4, 0.3, 0.3),
+            Self::Garbage => rgb(0.55, 0.55, 0.55),
+        }
+    }
+
+    pub fn is_garbage(self) -> bool {
+        self == Self::Garbage
+    }
+
+    /// The letter for the block in boards written out as text.
+    pub fn symbol(self) -> char {
+        match self {
+            Self::Red => 'R',
+            Self::Green => 'G',
+            Self::Blue => 'B',
+            Self::Orange => 'O',
+            Self::Purple => 'P',
+            Self::Yellow => 'Y',
+            Self::Cyan => 'C',
+            Self::Violet => 'V',
+            Self::White => 'W',
+            Self::Black => 'K',
+            Self::Garbage => '#',
         }
     }
+
+    pub fn from_symbol(symbol: char) -> Option<Self> {
+        (0..=Self::COLORS)
+            .filter_map(Self::from_usize)
+            .find(|block| block.symbol() == symbol)
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Palette {
     blocks: Vec<Block>,
 }
@@ -51,23 +94,34 @@ pub struct Palette {
 impl Palette {
     pub fn new(sz: usize) -> Self {
         Self {
-            blocks: (0..sz).filter_map(Block::from_usize).collect(),
+            blocks: (0..sz)
+                .filter_map(Block::from_usize)
+                .filter(|block| !block.is_garbage())
+                .collect(),
         }
     }
 
-    pub fn get_random<R: Rng>(&self, rng: &mut R) -> Block {
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn get_random<R: Rng + ?Sized>(&self, rng: &mut R) -> Block {
         let idx = rng.gen_range(0..self.blocks.len());
         self.blocks[idx]
     }
 
-    /// add another block color to palette,
+    /// add the first block color not already in the palette,
     /// returns false if we've run out of colors.
     pub fn expand(&mut self) -> bool {
-        if let Some(next) = Block::from_usize(self.blocks.len()) {
-            self.blocks.push(next);
-            true
-        } else {
-            false
+        let next = (0..Block::COLORS)
+            .filter_map(Block::from_usize)
+            .find(|block| !self.blocks.contains(block));
+        match next {
+            Some(next) => {
+                self.blocks.push(next);
+                true
+            }
+            None => false,
         }
     }
 }
@@ -79,6 +133,7 @@ pub enum BoardCell {
     Contains(Block),
 }
 
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Board {
     blocks: Vec<Vec<Option<Block>>>,
 }
@@ -117,7 +172,7 @@ impl Board {
         if self.x_len() <= x || self.y_len() <= y {
             return BoardCell::OutOfBounds;
         }
-        let cell = self[[x, y as usize]];
+        let cell = self[[x, y]];
         match cell {
             Some(block) => BoardCell::Contains(block),
             None => BoardCell::Empty,
@@ -134,8 +189,168 @@ impl Board {
             self.y_len() as f32 * tile_sz.y,
         )
     }
+
+    /// A board of size `sz` with the rows of `text` at the bottom, and empty above them.
+    pub fn from_bottom_rows(sz: [usize; 2], text: &str) -> Result<Self, ParseBoardError> {
+        let rows: Vec<&str> = text_rows(text).collect();
+        parse_rows(&rows, Some(sz))
+    }
+}
+
+/// Why some text isn't a board.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseBoardError {
+    NoRows,
+    BadHeader(String),
+    UnknownBlock {
+        symbol: char,
+        row: usize,
+        column: usize,
+    },
+    RaggedRow {
+        row: usize,
+        len: usize,
+        expected: usize,
+    },
+    TooManyRows {
+        rows: usize,
+        height: usize,
+    },
+}
+
+impl fmt::Display for ParseBoardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoRows => write!(f, "the board has no rows"),
+            Self::BadHeader(header) => write!(
+                f,
+                "the header {:?} should be the width and height, like 7x19",
+                header
+            ),
+            Self::UnknownBlock {
+                symbol,
+                row,
+                column,
+            } => write!(
+                f,
+                "{:?} in row {} column {} isn't a block, use one of .RGBOPYCVWK#",
+                symbol, row, column
+            ),
+            Self::RaggedRow { row, len, expected } => write!(
+                f,
+                "row {} is {} wide, the board is {} wide",
+                row, len, expected
+            ),
+            Self::TooManyRows { rows, height } => {
+                write!(f, "there are {} rows, the board is {} high", rows, height)
+            }
+        }
+    }
+}
+
+impl Error for ParseBoardError {}
+
+/// The lines of the text that aren't blank, without their indentation.
+fn text_rows(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().map(str::trim).filter(|row| !row.is_empty())
+}
+
+/// Read rows written top to bottom. Given a size, they fill the bottom of a board that big.
+fn parse_rows(rows: &[&str], sz: Option<[usize; 2]>) -> Result<Board, ParseBoardError> {
+    let width = match (sz, rows.first()) {
+        (Some([width, _]), _) => width,
+        (None, Some(row)) => row.chars().count(),
+        (None, None) => return Err(ParseBoardError::NoRows),
+    };
+    let height = sz.map_or(rows.len(), |[_, height]| height);
+    if width == 0 || height == 0 {
+        return Err(ParseBoardError::NoRows);
+    }
+    if height < rows.len() {
+        return Err(ParseBoardError::TooManyRows {
+            rows: rows.len(),
+            height,
+        });
+    }
+    let mut board = Board {
+        blocks: vec![vec![None; height]; width],
+    };
+    let top = height - rows.len();
+    for (i, row) in rows.iter().enumerate() {
+        let len = row.chars().count();
+        if len != width {
+            return Err(ParseBoardError::RaggedRow {
+                row: i + 1,
+                len,
+                expected: width,
+            });
+        }
+        for (x, symbol) in row.chars().enumerate() {
+            board[[x, top + i]] = match symbol {
+                '.' => None,
+                _ => Some(
+                    Block::from_symbol(symbol).ok_or(ParseBoardError::UnknownBlock {
+                        symbol,
+                        row: i + 1,
+                        column: x + 1,
+                    })?,
+                ),
+            };
+        }
+    }
+    Ok(board)
+}
+
+/// One letter per block and `.` for empty cells, a row per line from the top.
+/// The alternate form `{:#}` starts with a header giving the size, like `7x19`.
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            writeln!(f, "{}x{}", self.x_len(), self.y_len())?;
+        }
+        for y in 0..self.y_len() {
+            let row: String = (0..self.x_len())
+                .map(|x| self[[x, y]].map_or('.', Block::symbol))
+                .collect();
+            writeln!(f, "{}", row)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads what `Display` writes. With a header the rows can leave out the empty top of the board.
+impl FromStr for Board {
+    type Err = ParseBoardError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut rows: Vec<&str> = text_rows(text).collect();
+        let header = rows.first().and_then(|row| row.split_once('x'));
+        let sz = match header {
+            Some((width, height)) => {
+                let bad_header = || ParseBoardError::BadHeader(rows[0].to_owned());
+                let width = width.parse().map_err(|_| bad_header())?;
+                let height = height.parse().map_err(|_| bad_header())?;
+                rows.remove(0);
+                Some([width, height])
+            }
+            None => None,
+        };
+        parse_rows(&rows, sz)
+    }
 }
 
+/// Compare the bottom of a board with rows written out as text, showing both if they differ.
+#[cfg(test)]
+macro_rules! assert_board {
+    ($board:expr, $rows:expr) => {{
+        let board: &$crate::board::Board = &$board;
+        let expected = $crate::board::Board::from_bottom_rows(board.sz(), $rows).unwrap();
+        assert_eq!(board.to_string(), expected.to_string());
+    }};
+}
+#[cfg(test)]
+pub(crate) use assert_board;
+
 impl std::ops::Index<[usize; 2]> for Board {
     type Output = Option<Block>;
     fn index(&self, idx: [usize; 2]) -> &Self::Output {
@@ -164,14 +379,31 @@ impl std::ops::IndexMut<PilPos> for Board {
     }
 }
 
-pub fn find_matches(board: &Board, matches_required: u64) -> Vec<([usize; 2], Block)> {
+pub fn find_matches(board: &Board, rules: &GameRules) -> Vec<([usize; 2], Block)> {
+    match rules.match_rule {
+        MatchRule::ConnectedGroup => {
+            find_connected_matches(board, rules.matches_required, rules.grid())
+        }
+        MatchRule::Lines => find_line_matches(board, rules.matches_required, rules.grid()),
+    }
+}
+
+/// Blocks in a connected group of the same color at least `matches_required` large.
+pub fn find_connected_matches(
+    board: &Board,
+    matches_required: u64,
+    grid: &dyn Grid,
+) -> Vec<([usize; 2], Block)> {
     let mut matching = Vec::new();
     for x in 0..board.x_len() {
         for y in 0..board.y_len() {
             if let BoardCell::Contains(block) = board.get([x, y]) {
+                if block.is_garbage() {
+                    continue;
+                }
                 let search_fn = |n: [usize; 2]| {
                     let mut out = vec![];
-                    for &m in neighbours(n).iter() {
+                    for m in grid.neighbours(n, board.sz()) {
                         if board.get(m) == BoardCell::Contains(block) {
                             out.push(m);
                         }
@@ -188,6 +420,106 @@ pub fn find_matches(board: &Board, matches_required: u64) -> Vec<([usize; 2], Bl
     matching
 }
 
+/// Blocks in a straight run of the same color at least `matches_required` long.
+/// Blocks where runs cross are only listed once.
+/// On a cylinder, runs continue around the left and right edges.
+pub fn find_line_matches(
+    board: &Board,
+    matches_required: u64,
+    grid: &dyn Grid,
+) -> Vec<([usize; 2], Block)> {
+    let sz = board.sz();
+    let run_length = |start: [usize; 2], axis: usize, block: Block| {
+        // on a cylinder a run could otherwise go round and round forever
+        let mut run = HashSet::new();
+        run.insert(start);
+        for forward in [true, false] {
+            let mut cursor = start;
+            while let Some(next) = grid.step_along(cursor, axis, forward, sz) {
+                if board.get(next) != BoardCell::Contains(block) || !run.insert(next) {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        run.len()
+    };
+    let mut matching = Vec::new();
+    for x in 0..board.x_len() {
+        for y in 0..board.y_len() {
+            if let BoardCell::Contains(block) = board.get([x, y]) {
+                if block.is_garbage() {
+                    continue;
+                }
+                let in_line = (0..grid.line_axes())
+                    .any(|axis| matches_required <= run_length([x, y], axis, block) as u64);
+                if in_line {
+                    matching.push(([x, y], block));
+                }
+            }
+        }
+    }
+    matching
+}
+
+/// How many separate groups the matched blocks make up,
+/// where touching blocks of the same color count as one group.
+pub fn count_groups(matches: &[([usize; 2], Block)], board: &Board, rules: &GameRules) -> u64 {
+    let grid = rules.grid();
+    let sz = board.sz();
+    let cells: HashMap<[usize; 2], Block> = matches.iter().cloned().collect();
+    let touching = |n: [usize; 2]| match rules.match_rule {
+        MatchRule::ConnectedGroup => grid.neighbours(n, sz),
+        MatchRule::Lines => (0..grid.line_axes())
+            .flat_map(|axis| [true, false].map(|forward| grid.step_along(n, axis, forward, sz)))
+            .flatten()
+            .collect(),
+    };
+    let mut seen = HashSet::new();
+    let mut groups = 0;
+    for &(idx, block) in matches {
+        if seen.contains(&idx) {
+            continue;
+        }
+        groups += 1;
+        let group = search(idx, |n| {
+            touching(n)
+                .into_iter()
+                .filter(|m| cells.get(m) == Some(&block))
+                .collect()
+        });
+        seen.extend(group);
+    }
+    groups
+}
+
+/// Garbage touching any of the cells, each listed once.
+pub fn garbage_next_to(
+    board: &Board,
+    cells: &[([usize; 2], Block)],
+    grid: &dyn Grid,
+) -> Vec<[usize; 2]> {
+    let mut garbage = vec![];
+    for &(idx, _) in cells {
+        for n in grid.neighbours(idx, board.sz()) {
+            if board.get(n) == BoardCell::Contains(Block::Garbage) && !garbage.contains(&n) {
+                garbage.push(n);
+            }
+        }
+    }
+    garbage
+}
+
+/// Move every block up a row and put `row` in along the bottom.
+/// Anything in the top row is pushed off, but the game is over long before that.
+pub fn push_up(board: &mut Board, row: &[Option<Block>]) {
+    assert_eq!(row.len(), board.x_len());
+    for (column, &block) in board.blocks.iter_mut().zip(row) {
+        column.remove(0);
+        column.push(block);
+    }
+}
+
 pub fn write_pillar(board: &mut Board, pillar: &Pillar, PilPos { x, y }: PilPos) {
     let mut cursor = [x, y as usize - pillar.len()];
     for &block in pillar.iter() {
@@ -196,6 +528,20 @@ pub fn write_pillar(board: &mut Board, pillar: &Pillar, PilPos { x, y }: PilPos)
     }
 }
 
+/// Where a pillar at `pos` would come to rest if it dropped straight down.
+pub fn resting_pos(board: &Board, PilPos { x, y }: PilPos) -> PilPos {
+    let mut row = y as usize;
+    while board.get([x, row + 1]) == BoardCell::Empty {
+        row += 1;
+    }
+    PilPos {
+        x,
+        y: row as f32 + 1.0 - 0.0001,
+    }
+}
+
+/// Drop blocks straight down their columns until nothing is left floating.
+/// Columns are vertical on every grid, so this works for hex boards as well.
 pub fn fall_down(board: &mut Board) -> bool {
     let mut dropping = true;
     let mut drops = 0;
@@ -227,3 +573,175 @@ pub fn is_game_over(board: &Board, death_zone: usize) -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        count_groups, find_connected_matches, find_line_matches, push_up, Block, Board, Palette,
+        ParseBoardError,
+    };
+    use crate::{
+        grid::HexGrid,
+        the_rules::{GameRules, Topology},
+    };
+
+    #[test]
+    fn line_matches_include_diagonals_and_count_crossings_once() {
+        let mut board = Board::new([4, 4]);
+        for i in 0..3 {
+            board[[i, 3 - i]] = Some(Block::Red);
+            board[[i, 3]] = Some(Block::Red);
+        }
+        board[[3, 2]] = Some(Block::Green);
+        let matches = find_line_matches(&board, 3, &Topology::FourWay);
+        assert_eq!(matches.len(), 5);
+        assert!(matches.iter().all(|&(_, b)| b == Block::Red));
+        assert!(matches.contains(&([2, 1], Block::Red)));
+    }
+
+    #[test]
+    fn line_matches_ignore_bent_groups() {
+        let board: Board = "
+            ....
+            ....
+            .B..
+            BB..
+        "
+        .parse()
+        .unwrap();
+        assert!(find_line_matches(&board, 3, &Topology::FourWay).is_empty());
+    }
+
+    #[test]
+    fn topology_decides_which_blocks_touch() {
+        let board: Board = "
+            ....
+            ....
+            ..R.
+            R..R
+        "
+        .parse()
+        .unwrap();
+        assert!(find_connected_matches(&board, 3, &Topology::FourWay).is_empty());
+        assert!(find_connected_matches(&board, 3, &Topology::EightWay).is_empty());
+        assert!(find_connected_matches(&board, 3, &Topology::Cylinder).is_empty());
+        assert_eq!(
+            find_connected_matches(&board, 3, &Topology::EightWayCylinder).len(),
+            3
+        );
+    }
+
+    #[test]
+    fn line_matches_wrap_round_a_cylinder_without_counting_twice() {
+        let mut board = Board::new([3, 4]);
+        for x in 0..3 {
+            board[[x, 3]] = Some(Block::Green);
+        }
+        board[[0, 2]] = Some(Block::Blue);
+        board[[2, 2]] = Some(Block::Blue);
+        assert_eq!(find_line_matches(&board, 3, &Topology::Cylinder).len(), 3);
+        assert_eq!(find_line_matches(&board, 4, &Topology::Cylinder).len(), 0);
+    }
+
+    #[test]
+    fn hex_lines_follow_the_shifted_columns() {
+        let mut board = Board::new([4, 5]);
+        // down-right diagonal from an even column
+        board[[0, 1]] = Some(Block::Purple);
+        board[[1, 1]] = Some(Block::Purple);
+        board[[2, 2]] = Some(Block::Purple);
+        // a square diagonal, which isn't a line on a hex grid
+        board[[1, 4]] = Some(Block::Cyan);
+        board[[2, 3]] = Some(Block::Cyan);
+        board[[3, 2]] = Some(Block::Cyan);
+        let matches = find_line_matches(&board, 3, &HexGrid);
+        assert_eq!(matches.len(), 3);
+        assert!(matches.iter().all(|&(_, b)| b == Block::Purple));
+    }
+
+    #[test]
+    fn boards_round_trip_through_text() {
+        let text = "...\n..R\n.GR\nBGR\n";
+        let board: Board = text.parse().unwrap();
+        assert_eq!(board.sz(), [3, 4]);
+        assert_eq!(board[[0, 3]], Some(Block::Blue));
+        assert_eq!(board.to_string(), text);
+
+        let with_header = format!("{:#}", board);
+        assert!(with_header.starts_with("3x4\n"));
+        assert_eq!(with_header.parse::<Board>().unwrap(), board);
+        // the header lets the empty rows at the top be left out
+        assert_eq!("3x4\n..R\n.GR\nBGR".parse::<Board>().unwrap(), board);
+    }
+
+    #[test]
+    fn bad_board_text_is_explained() {
+        let err = "..R\n.XR".parse::<Board>().unwrap_err();
+        assert_eq!(
+            err,
+            ParseBoardError::UnknownBlock {
+                symbol: 'X',
+                row: 2,
+                column: 2
+            }
+        );
+        let err = "..R\n.GRR".parse::<Board>().unwrap_err();
+        assert_eq!(err.to_string(), "row 2 is 4 wide, the board is 3 wide");
+        let err = "3x1\n..R\n.GR".parse::<Board>().unwrap_err();
+        assert_eq!(err, ParseBoardError::TooManyRows { rows: 2, height: 1 });
+        assert!(matches!(
+            "3y4\n...".parse::<Board>(),
+            Err(ParseBoardError::UnknownBlock { symbol: '3', .. })
+        ));
+    }
+
+    #[test]
+    fn garbage_is_pushed_up_but_never_matches() {
+        let mut board: Board = "4x4\nR...\nR...".parse().unwrap();
+        let row = [
+            Some(Block::Red),
+            Some(Block::Garbage),
+            Some(Block::Garbage),
+            None,
+        ];
+        push_up(&mut board, &row);
+        assert_board!(board, "R...\nR...\nR##.");
+        let matches = find_connected_matches(&board, 2, &Topology::FourWay);
+        assert_eq!(matches.len(), 3);
+        assert!(matches.iter().all(|&(_, b)| b == Block::Red));
+    }
+
+    #[test]
+    fn groups_are_counted_by_what_touches() {
+        let mut board = Board::new([5, 4]);
+        let mut matches = vec![];
+        for y in 1..4 {
+            matches.push(([0, y], Block::Red));
+            matches.push(([4, y], Block::Red));
+        }
+        for &(idx, block) in &matches {
+            board[idx] = Some(block);
+        }
+        let rules = GameRules::default();
+        assert_eq!(count_groups(&matches, &board, &rules), 2);
+        let rules = GameRules {
+            topology: Topology::Cylinder,
+            ..Default::default()
+        };
+        assert_eq!(count_groups(&matches, &board, &rules), 1);
+    }
+
+    #[test]
+    fn palettes_expand_with_colors_they_dont_have() {
+        let mut palette: Palette = ron::from_str("(blocks: [Blue, Orange])").unwrap();
+        assert!(palette.expand());
+        assert!(palette.expand());
+        assert_eq!(
+            palette.blocks(),
+            &[Block::Blue, Block::Orange, Block::Red, Block::Green]
+        );
+        while palette.expand() {}
+        assert_eq!(palette.blocks().len(), Block::COLORS);
+        assert!(!palette.blocks().contains(&Block::Garbage));
+    }
+}
@@ -0,0 +1,487 @@
+use std::hash::Hash;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{
+        fall_down, find_matches, garbage_next_to, is_game_over, resting_pos, write_pillar, Board,
+        BoardCell,
+    },
+    input::{InputState, PlayerCommand},
+    pillar::Pillar,
+    simulation::{bot_rng, is_horizontal_move_valid, GameRng, Phase, PilPos, Simulation},
+    the_rules::{GameRules, GarbageRows},
+    timer::Timer,
+};
+
+/// Scored for a placement that tops out the board, below anything else.
+const TOPPED_OUT: f32 = -1.0e6;
+
+/// How far apart the scores of placements are pushed at no skill at all.
+const NOISE: f32 = 12.0;
+
+/// Below this skill the bot doesn't look ahead to the next pillar.
+const LOOKAHEAD_SKILL: f32 = 0.5;
+
+/// How fast and how well the bot plays.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BotSettings {
+    /// seconds between presses
+    pub press_every: f32,
+    /// from 0, placing nearly at random, to 1, always the placement it thinks best
+    pub skill: f32,
+}
+
+impl Default for BotSettings {
+    fn default() -> Self {
+        BOT_LEVELS[1].1
+    }
+}
+
+/// The bots to play against, by name.
+pub const BOT_LEVELS: [(&str, BotSettings); 3] = [
+    (
+        "easy",
+        BotSettings {
+            press_every: 0.3,
+            skill: 0.3,
+        },
+    ),
+    (
+        "normal",
+        BotSettings {
+            press_every: 0.12,
+            skill: 0.7,
+        },
+    ),
+    (
+        "hard",
+        BotSettings {
+            press_every: 0.04,
+            skill: 1.0,
+        },
+    ),
+];
+
+/// What each thing about a board is worth to the bot.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Weights {
+    /// for each block cleared
+    pub cleared: f32,
+    /// for each clear after the first in a chain, squared
+    pub chain: f32,
+    /// for each block in the stack
+    pub height: f32,
+    /// for each row short of twice a pillar's room left in a column, squared
+    pub danger: f32,
+    /// for each pair of touching blocks of the same color
+    pub clustering: f32,
+    /// for each group one block short of a match with an empty cell next to it
+    pub chain_potential: f32,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            cleared: 1.0,
+            chain: 4.0,
+            height: 0.15,
+            danger: 2.0,
+            clustering: 0.5,
+            chain_potential: 1.0,
+        }
+    }
+}
+
+/// What dropping a pillar somewhere leaves behind, once the board has settled.
+#[derive(Clone, Debug)]
+pub struct Landing {
+    pub board: Board,
+    /// where the pillar came to rest
+    pub pos: PilPos,
+    /// blocks cleared, garbage included
+    pub cleared: u64,
+    /// clears in a row
+    pub chain: u64,
+    pub topped_out: bool,
+}
+
+impl Landing {
+    /// What the landing sets off, for a hint.
+    pub fn describe(&self) -> String {
+        match self.chain {
+            0 => "no matches".to_owned(),
+            1 => format!("clears {} blocks", self.cleared),
+            chain => format!("chain of {}, {} blocks", chain, self.cleared),
+        }
+    }
+}
+
+/// Drop `pillar` straight down column `x` from height `y`, then clear and
+/// cascade the way the simulation would.
+/// Garbage turned into colors is left as garbage, as its colors can't be known.
+pub fn land(board: &Board, pillar: &Pillar, PilPos { x, y }: PilPos, rules: &GameRules) -> Landing {
+    let mut board = board.clone();
+    let pos = resting_pos(&board, PilPos { x, y });
+    let mut cleared = 0;
+    let mut chain = 0;
+    if pillar.is_magic() {
+        if let BoardCell::Contains(color) = board.get([x, pos.y as usize + 1]) {
+            for x in 0..board.x_len() {
+                for y in 0..board.y_len() {
+                    if board[[x, y]] == Some(color) {
+                        board[[x, y]] = None;
+                        cleared += 1;
+                    }
+                }
+            }
+        }
+        chain = u64::from(0 < cleared);
+    } else {
+        write_pillar(&mut board, pillar, pos);
+    }
+    loop {
+        fall_down(&mut board);
+        let matches = find_matches(&board, rules);
+        if matches.is_empty() {
+            break;
+        }
+        let garbage = garbage_next_to(&board, &matches, rules.grid());
+        for &(idx, _) in matches.iter() {
+            board[idx] = None;
+        }
+        cleared += matches.len() as u64;
+        if rules.garbage_rows != GarbageRows::Turning {
+            for &idx in garbage.iter() {
+                board[idx] = None;
+            }
+            cleared += garbage.len() as u64;
+        }
+        chain += 1;
+    }
+    let topped_out = is_game_over(&board, rules.pillar_sz);
+    Landing {
+        board,
+        pos,
+        cleared,
+        chain,
+        topped_out,
+    }
+}
+
+/// Columns the pillar at `from` can be moved to before it drops,
+/// with how many steps it takes to get there, to the left if negative.
+pub fn reachable_columns(board: &Board, from: PilPos, rules: &GameRules) -> Vec<(usize, isize)> {
+    let grid = rules.grid();
+    let mut columns = vec![(from.x, 0)];
+    for dx in [-1, 1] {
+        let mut x = from.x;
+        let mut steps = 0;
+        while let Some(next) = grid.horizontal_step(x, dx, board.x_len()) {
+            // on a cylinder the way round the other side may be shorter
+            if !is_horizontal_move_valid(next, from.y, board)
+                || columns.iter().any(|&(seen, _)| seen == next)
+            {
+                break;
+            }
+            x = next;
+            steps += dx;
+            columns.push((x, steps));
+        }
+    }
+    columns
+}
+
+/// Every way the pillar can be turned, each listed once, with how many times it is rotated up.
+fn rotations(pillar: &Pillar) -> Vec<(usize, Pillar)> {
+    let mut rotations: Vec<(usize, Pillar)> = vec![];
+    let mut rotated = pillar.clone();
+    for turns in 0..pillar.len().max(1) {
+        if rotations.iter().all(|(_, seen)| seen != &rotated) {
+            rotations.push((turns, rotated.clone()));
+        }
+        rotated.rot_up();
+    }
+    rotations
+}
+
+/// The stack's height, how close it is to the top,
+/// and how well its colors are grouped together.
+pub fn shape_score(board: &Board, rules: &GameRules, weights: &Weights) -> f32 {
+    let grid = rules.grid();
+    let sz = board.sz();
+    let mut score = 0.0;
+    for x in 0..board.x_len() {
+        let top = (0..board.y_len())
+            .find(|&y| board[[x, y]].is_some())
+            .unwrap_or_else(|| board.y_len());
+        score -= weights.height * (board.y_len() - top) as f32;
+        let room = top.saturating_sub(rules.pillar_sz + 1);
+        let short = (2 * rules.pillar_sz).saturating_sub(room) as f32;
+        score -= weights.danger * short * short;
+    }
+    let required = rules.matches_required as usize;
+    for x in 0..board.x_len() {
+        for y in 0..board.y_len() {
+            let block = match board[[x, y]] {
+                Some(block) if !block.is_garbage() => block,
+                _ => continue,
+            };
+            let neighbours = grid.neighbours([x, y], sz);
+            let alike = neighbours
+                .iter()
+                .filter(|&&n| board[n] == Some(block))
+                .count();
+            // each pair is seen from both ends
+            score += weights.clustering * alike as f32 / 2.0;
+            let open = neighbours.iter().any(|&n| board[n].is_none());
+            if open && 1 < required && alike + 1 == required - 1 {
+                score += weights.chain_potential / (required - 1) as f32;
+            }
+        }
+    }
+    score
+}
+
+/// Points for what a landing cleared straight away.
+fn landing_score(landing: &Landing, weights: &Weights) -> f32 {
+    let extra_clears = landing.chain.saturating_sub(1) as f32;
+    weights.cleared * landing.cleared as f32 + weights.chain * extra_clears * extra_clears
+}
+
+/// A place to drop a pillar and what the bot makes of it.
+#[derive(Clone, Debug)]
+pub struct Placement {
+    pub x: usize,
+    /// steps to the side from where the pillar is, to the left if negative
+    pub steps: isize,
+    /// times to rotate up from how the pillar is turned now
+    pub rotations: usize,
+    /// the pillar turned the way it lands
+    pub pillar: Pillar,
+    pub landing: Landing,
+    pub score: f32,
+}
+
+/// Every reachable column and rotation for `pillar` at `from`, scored with a look
+/// at where `next` would go after it if given.
+pub fn placements(
+    board: &Board,
+    pillar: &Pillar,
+    next: Option<&Pillar>,
+    from: PilPos,
+    rules: &GameRules,
+    weights: &Weights,
+) -> Vec<Placement> {
+    let mut found = vec![];
+    for (x, steps) in reachable_columns(board, from, rules) {
+        for (rotations, pillar) in rotations(pillar) {
+            let landing = land(board, &pillar, PilPos { x, y: from.y }, rules);
+            let score = if landing.topped_out {
+                TOPPED_OUT
+            } else {
+                let after = match next {
+                    Some(next) => {
+                        // the next pillar starts where every pillar does
+                        placements(
+                            &landing.board,
+                            next,
+                            None,
+                            rules.pillar_spawn_pt,
+                            rules,
+                            weights,
+                        )
+                        .into_iter()
+                        .map(|placement| placement.score)
+                        .fold(TOPPED_OUT, f32::max)
+                    }
+                    None => shape_score(&landing.board, rules, weights),
+                };
+                landing_score(&landing, weights) + after
+            };
+            found.push(Placement {
+                x,
+                steps,
+                rotations,
+                pillar,
+                landing,
+                score,
+            });
+        }
+    }
+    found
+}
+
+/// The best placement for the falling pillar, looking ahead to the next one.
+pub fn best_placement(sim: &Simulation, weights: &Weights) -> Option<Placement> {
+    let common = &sim.common;
+    placements(
+        &common.board,
+        &sim.current_pillar,
+        common.next_pillars.front(),
+        sim.pillar_pos,
+        &common.rules,
+        weights,
+    )
+    .into_iter()
+    .max_by(|a, b| a.score.total_cmp(&b.score))
+}
+
+/// Where the bot has decided to put the falling pillar.
+struct Plan {
+    /// the drop the plan was made for
+    drop_count: u64,
+    x: usize,
+    /// stepping left, or right
+    dx: isize,
+    pillar: Pillar,
+    /// the column the last step was pressed from, to notice the way being blocked
+    stepped_from: Option<usize>,
+}
+
+/// A player that picks a placement for each pillar and presses its way there,
+/// for a CPU opponent or to play by itself.
+pub struct Bot {
+    pub settings: BotSettings,
+    pub weights: Weights,
+    rng: GameRng,
+    plan: Option<Plan>,
+    press_timer: Timer,
+}
+
+impl Bot {
+    pub fn new(settings: BotSettings, seed: u64) -> Self {
+        Self {
+            settings,
+            weights: Weights::default(),
+            rng: bot_rng(seed),
+            plan: None,
+            press_timer: Timer::new(settings.press_every),
+        }
+    }
+
+    /// Press what comes next on `input_state`, before `sim` is updated with it.
+    /// Every press is let go of on the following frame, so each one is new.
+    pub fn update<I>(
+        &mut self,
+        sim: &Simulation,
+        time_delta: f32,
+        input_state: &mut InputState<I, PlayerCommand>,
+    ) where
+        I: Copy + Eq + Hash,
+    {
+        let pressed = PlayerCommand::GAMEPLAY
+            .iter()
+            .any(|&command| input_state.active(command));
+        for command in PlayerCommand::GAMEPLAY {
+            input_state.deactivate_command(command);
+        }
+        self.press_timer.update(time_delta);
+        if !matches!(sim.phase, Phase::Falling) {
+            self.plan = None;
+            return;
+        }
+        if pressed || !self.press_timer.has_elapsed() {
+            return;
+        }
+        let drop_count = sim.common.drop_count;
+        if self.plan.as_ref().map(|plan| plan.drop_count) != Some(drop_count) {
+            self.plan = self.choose(sim).map(|placement| Plan {
+                drop_count,
+                x: placement.x,
+                dx: placement.steps.signum(),
+                pillar: placement.pillar,
+                stepped_from: None,
+            });
+        }
+        let plan = match &mut self.plan {
+            Some(plan) => plan,
+            None => return,
+        };
+        let x = sim.pillar_pos.x;
+        let blocked = plan.stepped_from == Some(x);
+        let command = if x != plan.x && !blocked {
+            plan.stepped_from = Some(x);
+            if plan.dx < 0 {
+                PlayerCommand::MoveLeft
+            } else {
+                PlayerCommand::MoveRight
+            }
+        } else if sim.current_pillar != plan.pillar {
+            PlayerCommand::RotUp
+        } else {
+            PlayerCommand::HardDrop
+        };
+        input_state.activate_command(command);
+        self.press_timer.set(self.settings.press_every);
+    }
+
+    /// The placement the bot goes for, which is the best one less often the less skilled it is.
+    fn choose(&mut self, sim: &Simulation) -> Option<Placement> {
+        let skill = self.settings.skill.clamp(0.0, 1.0);
+        let next = sim.common.next_pillars.front();
+        let next = next.filter(|_| LOOKAHEAD_SKILL <= skill);
+        let common = &sim.common;
+        let placements = placements(
+            &common.board,
+            &sim.current_pillar,
+            next,
+            sim.pillar_pos,
+            &common.rules,
+            &self.weights,
+        );
+        placements
+            .into_iter()
+            .map(|placement| {
+                let noise = self.rng.gen_range(-1.0..=1.0) * (1.0 - skill) * NOISE;
+                (placement.score + noise, placement)
+            })
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, placement)| placement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{best_placement, Bot, BotSettings, Weights};
+    use crate::board::{Block::*, Board};
+    use crate::input::{default_input_cfg, InputState};
+    use crate::pillar::Pillar;
+    use crate::simulation::Simulation;
+    use crate::the_rules::GameRules;
+
+    #[test]
+    fn the_best_placement_sets_off_the_chain() {
+        let mut sim = Simulation::new_game(GameRules::default(), 3);
+        let sz = sim.common.board.sz();
+        sim.common.board = Board::from_bottom_rows(sz, "BB.....\nRR.GG..").unwrap();
+        sim.current_pillar = Pillar::Blocks(vec![Green, Red, Blue]);
+        sim.common.next_pillars.clear();
+
+        let placement = best_placement(&sim, &Weights::default()).unwrap();
+        assert_eq!(placement.x, 2);
+        assert_eq!(placement.pillar, Pillar::Blocks(vec![Blue, Green, Red]));
+        assert_eq!(placement.landing.chain, 3);
+        assert_eq!(placement.landing.cleared, 9);
+    }
+
+    #[test]
+    fn the_bot_clears_blocks_by_itself() {
+        let mut sim = Simulation::new_game(GameRules::default(), 5);
+        let settings = BotSettings {
+            press_every: 0.0,
+            skill: 1.0,
+        };
+        let mut bot = Bot::new(settings, 5);
+        let mut input_state = InputState::new(default_input_cfg());
+        for _ in 0..60 * 60 {
+            bot.update(&sim, 1.0 / 60.0, &mut input_state);
+            sim.update(1.0 / 60.0, &input_state);
+            input_state.save_current();
+        }
+        assert!(!sim.is_game_over());
+        assert!(30 < sim.common.drop_count);
+        assert!(60 < sim.common.blocks_cleared);
+    }
+}
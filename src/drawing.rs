@@ -6,10 +6,16 @@ use ggez::{
     Context, GameResult,
 };
 use glam::{vec2, vec4, Vec2};
+use num_traits::FromPrimitive;
 
 use crate::{
-    assets::Assets, board::Board, game_loop::CommonState, helpful_things::HalfSizeCtx,
+    assets::Assets,
+    board::{Block, Board},
+    grid::Grid,
+    helpful_things::HalfSizeCtx,
     pillar::Pillar,
+    simulation::{CommonState, PilPos},
+    the_rules::{format_clock, GameMode, RiseEvery},
 };
 
 pub const fn rgb(r: f32, g: f32, b: f32) -> Color {
@@ -26,6 +32,10 @@ pub const YELLOW: Color = rgb(1.0, 1.0, 0.0);
 pub const VIOLET: Color = rgb(0.5, 0.0, 1.0);
 pub const GOLD: Color = rgb(212. / 255., 175. / 255., 37. / 255.);
 
+/// The window for one board, versus is twice as wide.
+pub const WINDOW_WIDTH: f32 = 480.0;
+pub const WINDOW_HEIGHT: f32 = 736.0;
+
 #[derive(Clone, Copy, Debug)]
 pub struct DrawingPlans {
     pub board_pos: Vec2,
@@ -101,12 +111,22 @@ pub fn expand_rect(r: Rect, e: Vec2) -> Rect {
     }
 }
 
-pub fn draw_board(board: &Board, tile_sz: Vec2, tile_image: &Image) -> impl Drawable {
+/// Top left corner of the tile at `[x, y]`, relative to the board.
+pub fn tile_pos(grid: &dyn Grid, x: usize, y: f32, tile_sz: Vec2) -> Vec2 {
+    vec2(x as f32 * tile_sz.x, (y + grid.column_shift(x)) * tile_sz.y)
+}
+
+pub fn draw_board(
+    board: &Board,
+    grid: &dyn Grid,
+    tile_sz: Vec2,
+    tile_image: &Image,
+) -> impl Drawable {
     let mut sprite_batch = SpriteBatch::new(tile_image.clone());
     for x_idx in 0..board.x_len() {
         for y_idx in 0..board.y_len() {
             if let Some(block) = board[[x_idx, y_idx]] {
-                let target = vec2(x_idx as f32 * tile_sz.x, y_idx as f32 * tile_sz.y);
+                let target = tile_pos(grid, x_idx, y_idx as f32, tile_sz);
                 sprite_batch.add((target, block.color()));
             }
         }
@@ -116,6 +136,7 @@ pub fn draw_board(board: &Board, tile_sz: Vec2, tile_image: &Image) -> impl Draw
 
 pub fn draw_board_with_highlight(
     board: &Board,
+    grid: &dyn Grid,
     tile_sz: Vec2,
     tile_image: Image,
     highlighted: &[[usize; 2]],
@@ -137,7 +158,7 @@ pub fn draw_board_with_highlight(
                 } else {
                     block.color()
                 };
-                let target = vec2(x_idx as f32 * tile_sz.x, y_idx as f32 * tile_sz.y);
+                let target = tile_pos(grid, x_idx, y_idx as f32, tile_sz);
                 sprite_batch.add((target, color));
             }
         }
@@ -145,6 +166,19 @@ pub fn draw_board_with_highlight(
     sprite_batch
 }
 
+/// Colors of the pillar's blocks from top to bottom.
+/// Magic pillars cycle through every block color over time `t`.
+pub fn pillar_colors(pillar: &Pillar, t: f32) -> Vec<Color> {
+    if pillar.is_magic() {
+        (0..pillar.len())
+            .filter_map(|row| Block::from_usize(((8.0 * t) as usize + row) % Block::COLORS))
+            .map(Block::color)
+            .collect()
+    } else {
+        pillar.iter().map(|block| block.color()).collect()
+    }
+}
+
 pub fn lerp_color(c: Color, d: Color, s: f32) -> Color {
     let cv = vec4(c.r, c.g, c.b, c.a);
     let dv = vec4(d.r, d.g, d.b, d.a);
@@ -152,49 +186,192 @@ pub fn lerp_color(c: Color, d: Color, s: f32) -> Color {
     Color::new(r.x, r.y, r.z, r.w)
 }
 
-pub fn draw_game_play(
+/// Where the board goes, `offset` from the middle of the window.
+pub fn game_play_plans(
     ctx: &mut Context,
     assets: &Assets,
     common: &CommonState,
-    pillar: Option<(&Pillar, usize, f32)>,
-    highlights: Option<(&[[usize; 2]], f32)>,
-) -> GameResult {
+    offset: Vec2,
+) -> DrawingPlans {
     let board = &common.board;
-    let tile_sz = assets.tile_sz;
-    let plans = DrawingPlans::new(
+    let grid = common.rules.grid();
+    let mut plans = DrawingPlans::new(
         ctx,
-        tile_sz,
+        assets.tile_sz,
         board.sz(),
         common.rules.pillar_spawn_pt.into(),
         common.rules.pillar_sz,
-        vec2(0.0, 0.0),
+        offset,
     );
+    // make room for the bottom of the columns that are pushed down
+    let overhang = (0..board.x_len())
+        .map(|x| grid.column_shift(x))
+        .fold(0.0, f32::max);
+    plans.board_rect.h += overhang * assets.tile_sz.y;
+    plans
+}
+
+/// Draw a see-through copy of the pillar at `pos`,
+/// and outline the `marked` cells of the board.
+pub fn draw_ghost_pillar(
+    ctx: &mut Context,
+    assets: &Assets,
+    common: &CommonState,
+    pillar: &Pillar,
+    pos: PilPos,
+    marked: &[[usize; 2]],
+    offset: Vec2,
+) -> GameResult {
+    let grid = common.rules.grid();
+    let tile_sz = assets.tile_sz;
+    let plans = game_play_plans(ctx, assets, common, offset);
+    let t = ggez::timer::time_since_start(ctx).as_secs_f32();
+    let mut sprite_batch = SpriteBatch::new(assets.block_image.clone());
+    let ghost_pos = tile_pos(grid, pos.x, pos.y - pillar.len() as f32, tile_sz);
+    for (row, color) in pillar_colors(pillar, t).into_iter().enumerate() {
+        let target = ghost_pos + vec2(0.0, row as f32 * tile_sz.y);
+        sprite_batch.add((target, Color { a: 0.3, ..color }));
+    }
+    graphics::draw(ctx, &sprite_batch, (plans.board_pos,))?;
+
+    if !marked.is_empty() {
+        let stroke_options = StrokeOptions::default().with_line_width(2.0);
+        let mut primitives_mesh_builder = MeshBuilder::new();
+        for &[x, y] in marked {
+            let corner = tile_pos(grid, x, y as f32, tile_sz);
+            let rect = Rect::new(corner.x, corner.y, tile_sz.x, tile_sz.y);
+            primitives_mesh_builder.rectangle(
+                DrawMode::Stroke(stroke_options),
+                rect,
+                Color::WHITE,
+            )?;
+        }
+        let mesh = primitives_mesh_builder.build(ctx)?;
+        graphics::draw(ctx, &mesh, (plans.board_pos,))?;
+    }
+    Ok(())
+}
+
+/// A hinted placement, outlined in the colors the pillar would land in,
+/// with a tooltip above it saying what it would set off.
+pub fn draw_hint(
+    ctx: &mut Context,
+    assets: &Assets,
+    common: &CommonState,
+    pillar: &Pillar,
+    pos: PilPos,
+    tooltip: &str,
+    offset: Vec2,
+) -> GameResult {
+    let grid = common.rules.grid();
+    let tile_sz = assets.tile_sz;
+    let plans = game_play_plans(ctx, assets, common, offset);
+    let t = ggez::timer::time_since_start(ctx).as_secs_f32();
+    let top = tile_pos(grid, pos.x, pos.y - pillar.len() as f32, tile_sz);
+    let stroke_options = StrokeOptions::default().with_line_width(3.0);
+    let mut outlines = MeshBuilder::new();
+    for (row, color) in pillar_colors(pillar, t).into_iter().enumerate() {
+        let corner = top + vec2(1.5, row as f32 * tile_sz.y + 1.5);
+        let rect = Rect::new(corner.x, corner.y, tile_sz.x - 3.0, tile_sz.y - 3.0);
+        outlines.rectangle(DrawMode::Stroke(stroke_options), rect, color)?;
+    }
+    let mesh = outlines.build(ctx)?;
+    graphics::draw(ctx, &mesh, (plans.board_pos,))?;
+
+    // kept inside the board, whichever column the hint is in
+    let text = Text::new((tooltip, assets.font, 16.0));
+    let (w, h) = (text.width(ctx), text.height(ctx));
+    let board_w = common.board.px_sz(tile_sz).x;
+    let x = (top.x + 0.5 * (tile_sz.x - w)).clamp(4.0, (board_w - w - 4.0).max(4.0));
+    let corner = plans.board_pos + vec2(x, top.y - h - 10.0);
+    let rect = Rect::new(corner.x - 4.0, corner.y - 2.0, w + 8.0, h + 4.0);
+    let background = MeshBuilder::new()
+        .rectangle(DrawMode::fill(), rect, Color::new(0.0, 0.0, 0.0, 0.8))?
+        .rectangle(DrawMode::stroke(1.0), rect, Color::WHITE)?
+        .build(ctx)?;
+    graphics::draw(ctx, &background, (Vec2::ZERO,))?;
+    graphics::draw(ctx, &text, (corner, Color::WHITE))
+}
+
+pub fn draw_game_play(
+    ctx: &mut Context,
+    assets: &Assets,
+    common: &CommonState,
+    pillar: Option<(&Pillar, usize, f32)>,
+    highlights: Option<(&[[usize; 2]], f32)>,
+    offset: Vec2,
+) -> GameResult {
+    let board = &common.board;
+    let grid = common.rules.grid();
+    let tile_sz = assets.tile_sz;
+    let plans = game_play_plans(ctx, assets, common, offset);
     draw_borders(ctx, plans, 3.0)?;
     let mut sprite_batch = SpriteBatch::new(assets.block_image.clone());
-    let next_pillar_pos = vec2(
-        common.rules.pillar_spawn_pt.x as f32 * tile_sz.x,
-        (common.rules.pillar_spawn_pt.y - common.rules.pillar_sz as f32) * tile_sz.y,
+    let next_pillar_pos = tile_pos(
+        grid,
+        common.rules.pillar_spawn_pt.x,
+        common.rules.pillar_spawn_pt.y - common.rules.pillar_sz as f32,
+        tile_sz,
     );
-    for (row, block) in common.next_pillar.iter().enumerate() {
-        let target = next_pillar_pos + vec2(0.0, row as f32 * tile_sz.y);
-        sprite_batch.add((target, block.color()));
+    let t = ggez::timer::time_since_start(ctx).as_secs_f32();
+    let mut upcoming = common.next_pillars.iter();
+    if let Some(next_pillar) = upcoming.next() {
+        for (row, color) in pillar_colors(next_pillar, t).into_iter().enumerate() {
+            let target = next_pillar_pos + vec2(0.0, row as f32 * tile_sz.y);
+            sprite_batch.add((target, color));
+        }
     }
 
     if let Some((pillar, x, y)) = pillar {
-        let pillar_pos = vec2(
-            x as f32 * tile_sz.x,
-            (y - common.rules.pillar_sz as f32) * tile_sz.y,
-        );
-        for (row, block) in pillar.iter().enumerate() {
+        let pillar_pos = tile_pos(grid, x, y - common.rules.pillar_sz as f32, tile_sz);
+        for (row, color) in pillar_colors(pillar, t).into_iter().enumerate() {
             let target = pillar_pos + vec2(0.0, row as f32 * tile_sz.y);
-            sprite_batch.add((target, block.color()));
+            sprite_batch.add((target, color));
+        }
+    }
+
+    // the hold slot sits to the left of the board
+    let hold_pos = vec2(-(tile_sz.x + 8.0), plans.line_of_death + 16.0);
+    if common.rules.hold_enabled {
+        let hold_label = graphics::Text::new(("hold", assets.font, 12.0));
+        graphics::draw(
+            ctx,
+            &hold_label,
+            (plans.board_pos + hold_pos - vec2(0.0, 16.0),),
+        )?;
+    }
+    if let Some(held) = &common.held_pillar {
+        for (row, color) in pillar_colors(held, t).into_iter().enumerate() {
+            let target = hold_pos + vec2(0.0, row as f32 * tile_sz.y);
+            sprite_batch.add((target, color));
+        }
+    }
+
+    // the rest of the queue sits to the right of the board, soonest at the top
+    let queue_pos = vec2(plans.board_rect.w + 8.0, plans.line_of_death + 16.0);
+    if 1 < common.next_pillars.len() {
+        let queue_label = graphics::Text::new(("next", assets.font, 12.0));
+        graphics::draw(
+            ctx,
+            &queue_label,
+            (plans.board_pos + queue_pos - vec2(0.0, 16.0),),
+        )?;
+    }
+    // puzzles can have more pillars lined up than the rules show
+    let mut y = 0.0;
+    for pillar in upcoming.take(common.rules.preview_len.saturating_sub(1)) {
+        for (row, color) in pillar_colors(pillar, t).into_iter().enumerate() {
+            let target = queue_pos + vec2(0.0, y + row as f32 * tile_sz.y);
+            sprite_batch.add((target, color));
         }
+        y += (pillar.len() as f32 + 0.5) * tile_sz.y;
     }
     graphics::draw(ctx, &sprite_batch, (plans.board_pos,))?;
 
     if let Some((matches, s)) = highlights {
         let d = &draw_board_with_highlight(
             board,
+            grid,
             tile_sz,
             assets.block_image.clone(),
             matches,
@@ -205,22 +382,82 @@ pub fn draw_game_play(
     } else {
         graphics::draw(
             ctx,
-            &draw_board(board, tile_sz, &assets.block_image),
+            &draw_board(board, grid, tile_sz, &assets.block_image),
             (plans.board_pos,),
         )?;
     }
 
-    let level_message = graphics::Text::new((format!("level {}", common.level), assets.font, 25.0));
+    let status = match common.rules.mode {
+        GameMode::Marathon => format!("level {}", common.level),
+        GameMode::TimeAttack { seconds } => format!(
+            "level {}  {}",
+            common.level,
+            format_clock(seconds as f32 - common.clock)
+        ),
+        GameMode::Sprint { blocks } => format!(
+            "level {}  {} to go  {}",
+            common.level,
+            blocks.saturating_sub(common.blocks_cleared),
+            format_clock(common.clock)
+        ),
+        GameMode::RisingFloor {
+            every: RiseEvery::Seconds(seconds),
+        } => {
+            let next = seconds * (common.floor_rises + 1);
+            let left = (next as f32 - common.clock).max(0.0).ceil();
+            format!("level {}  rises in {}s", common.level, left)
+        }
+        GameMode::RisingFloor {
+            every: RiseEvery::Drops(drops),
+        } => {
+            let next = drops * (common.floor_rises + 1);
+            let left = next.saturating_sub(common.drop_count);
+            format!("level {}  rises in {} drops", common.level, left)
+        }
+        GameMode::Puzzle => {
+            // the falling pillar hasn't been used up yet
+            let left = common.next_pillars.len() + usize::from(pillar.is_some());
+            let goal = common.rules.puzzle().map(|p| p.goal.describe());
+            format!("{}  {} left", goal.unwrap_or_default(), left)
+        }
+    };
+    let level_message = graphics::Text::new((status, assets.font, 25.0));
+    let center_x = plans.board_pos.x + plans.board_ctr.x;
     let target = vec2(
-        graphics::screen_coordinates(ctx).center().x - level_message.half_sz(ctx).x,
-        plans.board_pos.y + plans.board_rect.bottom() as f32 + 15.0,
+        center_x - level_message.half_sz(ctx).x,
+        plans.board_pos.y + plans.board_rect.bottom() + 15.0,
     );
     graphics::draw(ctx, &level_message, (target,))?;
+
+    if let Some(points) = common.last_score {
+        let mut y = target.y + level_message.height(ctx) + 4.0;
+        let lines = [
+            format!("chain {}  groups {}", points.chain, points.groups),
+            format!(
+                "{} + {} + {} + {} = {}",
+                points.base,
+                points.chain_bonus,
+                points.group_bonus,
+                points.level_bonus,
+                points.total()
+            ),
+        ];
+        for line in lines {
+            let text = graphics::Text::new((line, assets.font, 14.0));
+            draw_text_centered_on(ctx, &text, vec2(center_x, y), Color::WHITE)?;
+            y += text.height(ctx);
+        }
+    }
     Ok(())
 }
 
 pub fn draw_centered_text(ctx: &mut Context, t: &Text, y: f32, color: Color) -> GameResult {
-    let x = graphics::screen_coordinates(ctx).center().x - 0.5 * t.width(ctx) as f32;
-    let v = vec2(x, y);
+    let x = graphics::screen_coordinates(ctx).center().x;
+    draw_text_centered_on(ctx, t, vec2(x, y), color)
+}
+
+/// Draw the text with the middle of its top edge at `pos`.
+pub fn draw_text_centered_on(ctx: &mut Context, t: &Text, pos: Vec2, color: Color) -> GameResult {
+    let v = vec2(pos.x - 0.5 * t.width(ctx), pos.y);
     graphics::draw(ctx, t, (v, color))
 }
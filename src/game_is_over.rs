@@ -1,3 +1,5 @@
+use std::error::Error;
+
 use ggez::{
     graphics::{self, Color, DrawMode},
     GameResult,
@@ -9,38 +11,54 @@ use crate::input::*;
 use crate::{
     assets::Assets,
     drawing::{draw_centered_text, draw_game_play, lerp_color, RED, YELLOW},
-    game_loop::{CommonState, GameState},
+    game_loop::GameState,
     helpful_things::time_delta,
-    scoring::{save_high_score_table, HighScore, HIGH_SCORE_PATH},
+    scoring::{
+        high_score_path, load_high_scores_table, save_high_score_table, HighScore, HighScoresTable,
+        SprintTime,
+    },
+    simulation::{CommonState, Ending},
+    the_rules::{format_clock, GameMode},
     timer::Timer,
     title_screen::TitleScreen,
 };
 
+/// The results of a game, where the player can put their name in the high scores.
 pub struct GameIsOver {
     pub common: CommonState,
+    pub ending: Ending,
+    pub score: u64,
     pub timer: Timer,
     pub name: [usize; 3],
     pub cursor: usize,
     pub cursor_cooldown: f32,
     pub fade: f32,
-    pub rank: usize,
+    /// place in the mode's high scores, `None` if the game doesn't qualify
+    pub rank: Option<usize>,
+    /// the high scores the game goes in, loaded once when it ended
+    table: Option<ModeTable>,
+}
+
+/// A table of either kind, so a new entry can be ranked and saved.
+enum ModeTable {
+    /// kept in the assets, where the title screen and the HUD show them
+    Marathon,
+    Scores(HighScoresTable<HighScore>),
+    Times(HighScoresTable<SprintTime>),
 }
 
 impl GameIsOver {
-    pub fn new(common: CommonState, assets: &Assets, score: u64) -> Self {
-        let rank = 1 + assets.high_score_table.borrow().find_position(&HighScore {
-            score,
-            level: common.level,
-            handle: "".to_owned(),
-        });
-        let cs = assets
-            .high_score_table
-            .borrow()
-            .prev()
+    pub fn new(common: CommonState, ending: Ending, assets: &Assets, score: u64) -> Self {
+        let table = ModeTable::load(&common, ending);
+        let rank = table
+            .as_ref()
+            .map(|table| 1 + table.find_position(&common, score, assets));
+        let cs = table
             .as_ref()
-            .map_or([0; 3], |h| {
+            .and_then(|table| table.prev_handle(assets))
+            .map_or([0; 3], |handle| {
                 let abet: Vec<char> = ('a'..='z').collect();
-                let chars = h.handle.chars().take(3);
+                let chars = handle.chars().take(3);
                 let ons: Vec<_> = chars
                     .map(|c| abet.binary_search(&c).map_or(0, |i| i + 1))
                     .collect();
@@ -49,12 +67,128 @@ impl GameIsOver {
 
         Self {
             common,
+            ending,
+            score,
             timer: Timer::new(0.0),
             name: cs,
             cursor: 0,
             cursor_cooldown: 0.25,
             fade: 0.0,
             rank,
+            table,
+        }
+    }
+
+    fn headline(&self) -> &'static str {
+        match self.ending {
+            Ending::ToppedOut => "GAME OVER",
+            Ending::TimeUp => "TIME UP",
+            Ending::Finished => "FINISHED",
+            Ending::OutOfPillars => "OUT OF PILLARS",
+        }
+    }
+
+    /// What the game is ranked by.
+    fn result(&self) -> String {
+        match self.common.rules.mode {
+            GameMode::Sprint { .. } if self.ending == Ending::Finished => {
+                format!("time {}", format_clock(self.common.clock))
+            }
+            GameMode::Sprint { blocks } => {
+                format!("{} of {} blocks", self.common.blocks_cleared, blocks)
+            }
+            _ => format!("score {}", self.score),
+        }
+    }
+}
+
+fn high_score(common: &CommonState, score: u64, handle: &str) -> HighScore {
+    HighScore {
+        score,
+        level: common.level,
+        handle: handle.to_owned(),
+        hinted: common.hinted,
+    }
+}
+
+fn sprint_time(common: &CommonState, handle: &str) -> SprintTime {
+    SprintTime {
+        millis: (common.clock * 1000.0) as u64,
+        level: common.level,
+        handle: handle.to_owned(),
+        hinted: common.hinted,
+    }
+}
+
+impl ModeTable {
+    /// The high scores this game would go in.
+    fn load(common: &CommonState, ending: Ending) -> Option<Self> {
+        let mode = common.rules.mode;
+        match (mode, ending) {
+            (GameMode::Marathon, _) => Some(Self::Marathon),
+            (GameMode::TimeAttack { .. }, _) | (GameMode::RisingFloor { .. }, _) => {
+                Some(Self::Scores(
+                    load_high_scores_table(high_score_path(mode))
+                        .unwrap_or_else(|_| HighScoresTable::new()),
+                ))
+            }
+            (GameMode::Sprint { .. }, Ending::Finished) => Some(Self::Times(
+                load_high_scores_table(high_score_path(mode))
+                    .unwrap_or_else(|_| HighScoresTable::new()),
+            )),
+            // sprints that top out don't count
+            (GameMode::Sprint { .. }, _) | (GameMode::Puzzle, _) => None,
+        }
+    }
+
+    fn find_position(&self, common: &CommonState, score: u64, assets: &Assets) -> usize {
+        match self {
+            Self::Marathon => assets
+                .high_score_table
+                .borrow()
+                .find_position(&high_score(common, score, "")),
+            Self::Scores(table) => table.find_position(&high_score(common, score, "")),
+            Self::Times(table) => table.find_position(&sprint_time(common, "")),
+        }
+    }
+
+    /// The name last put in this table, to start from.
+    fn prev_handle(&self, assets: &Assets) -> Option<String> {
+        match self {
+            Self::Marathon => assets
+                .high_score_table
+                .borrow()
+                .prev()
+                .as_ref()
+                .map(|h| h.handle.clone()),
+            Self::Scores(table) => table.prev().as_ref().map(|h| h.handle.clone()),
+            Self::Times(table) => table.prev().as_ref().map(|t| t.handle.clone()),
+        }
+    }
+
+    /// Put the game in the table and save it.
+    fn insert(
+        &mut self,
+        common: &CommonState,
+        score: u64,
+        assets: &Assets,
+        handle: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = high_score_path(common.rules.mode);
+        match self {
+            Self::Marathon => {
+                let mut high_scores = assets.high_score_table.borrow_mut();
+                high_scores.insert(high_score(common, score, handle));
+                save_high_score_table(path, &high_scores)
+            }
+            Self::Scores(high_scores) => {
+                high_scores.insert(high_score(common, score, handle));
+                save_high_score_table(path, high_scores)
+            }
+            Self::Times(times) => {
+                times.insert(sprint_time(common, handle));
+                save_high_score_table(path, times)
+            }
         }
     }
 }
@@ -69,18 +203,19 @@ impl GameState for GameIsOver {
     ) -> Option<Box<dyn GameState>> {
         self.fade = (self.fade + 0.5 * time_delta(ctx)).min(0.9);
         if input_state.just_active(PlayerCommand::Start) {
+            if self.rank.is_none() {
+                return Some(Box::new(TitleScreen::new()));
+            }
             let [a, b, c] = self.name;
             if 0 < a && 0 < b && 0 < c {
                 let chars: Vec<char> = ('a'..='z').collect();
-                let name = [chars[a - 1], chars[b - 1], chars[c - 1]];
-                let h = HighScore {
-                    score: commands.get_score(),
-                    level: self.common.level,
-                    handle: name.iter().collect(),
-                };
-                let mut high_scores = assets.high_score_table.borrow_mut();
-                high_scores.insert(h);
-                save_high_score_table(HIGH_SCORE_PATH, &high_scores).unwrap();
+                let name: String = [chars[a - 1], chars[b - 1], chars[c - 1]].iter().collect();
+                let score = commands.get_score();
+                if let Some(table) = &mut self.table {
+                    if let Err(e) = table.insert(&self.common, score, assets, &name) {
+                        eprintln!("couldn't save the high scores: {}", e);
+                    }
+                }
                 let next_state = TitleScreen::new();
                 return Some(Box::new(next_state));
             }
@@ -112,13 +247,13 @@ impl GameState for GameIsOver {
     }
 
     fn draw(&self, ctx: &mut ggez::Context, assets: &Assets) -> GameResult {
-        draw_game_play(ctx, assets, &self.common, None, None)?;
+        draw_game_play(ctx, assets, &self.common, None, None, Vec2::ZERO)?;
         let rect = graphics::screen_coordinates(ctx);
         let mesh = MeshBuilder::new()
             .rectangle(DrawMode::fill(), rect, Color::new(0., 0., 0., self.fade))?
             .build(ctx)?;
         graphics::draw(ctx, &mesh, (Vec2::ZERO,))?;
-        let game_over_message = graphics::Text::new(("GAME OVER", assets.font, 36.0));
+        let game_over_message = graphics::Text::new((self.headline(), assets.font, 36.0));
 
         let y = graphics::screen_coordinates(ctx).center().y - 200.0;
         draw_centered_text(
@@ -131,8 +266,22 @@ impl GameState for GameIsOver {
                 ggez::timer::time_since_start(ctx).as_secs_f32().sin().abs(),
             ),
         )?;
-        let y = y + 100.0;
-        let rank_msg = graphics::Text::new((format!("rank #{}", self.rank), assets.font, 36.));
+        let result_msg = graphics::Text::new((self.result(), assets.font, 25.0));
+        draw_centered_text(ctx, &result_msg, y + 45.0, Color::WHITE)?;
+        let hinted = if self.common.hinted {
+            "  played with hints *"
+        } else {
+            ""
+        };
+        let seed_msg = format!("seed {}{}", self.common.seed, hinted);
+        let seed_msg = graphics::Text::new((seed_msg, assets.font, 18.0));
+        draw_centered_text(ctx, &seed_msg, y + 75.0, Color::WHITE)?;
+        let y = y + 120.0;
+        let rank = match self.rank {
+            Some(rank) => rank,
+            None => return Ok(()),
+        };
+        let rank_msg = graphics::Text::new((format!("rank #{}", rank), assets.font, 36.));
         draw_centered_text(ctx, &rank_msg, y, Color::WHITE)?;
 
         let chars: Vec<char> = ('a'..='z').collect();
@@ -157,13 +306,13 @@ impl GameState for GameIsOver {
             })
             .collect();
         let gap = 50.0;
-        let w: f32 = text_frags.iter().map(|f| f.width(ctx) as f32).sum::<f32>()
+        let w: f32 = text_frags.iter().map(|f| f.width(ctx)).sum::<f32>()
             + (text_frags.len() - 1) as f32 * 50.0;
         let x = graphics::screen_coordinates(ctx).center().x - 0.5 * w;
         let mut target = vec2(x, y + 100.0);
         for f in text_frags {
             graphics::draw(ctx, &f, (target,))?;
-            target.x += gap + f.width(ctx) as f32;
+            target.x += gap + f.width(ctx);
         }
         Ok(())
     }
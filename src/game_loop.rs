@@ -1,11 +1,29 @@
+use std::time::Duration;
+
+use crate::assets::Assets;
 use crate::input::*;
-use crate::{assets::Assets, the_rules::GameRules};
 use crate::{
-    board::*, helpful_things::time_delta, magic_puffs::Puff, pillar::Pillar, title_screen,
+    drawing::{tile_pos, DrawingPlans},
+    exploding_blocks::ExplodingBlocks,
+    game_is_over::GameIsOver,
+    helpful_things::time_delta,
+    magic_puffs::{self, Puff},
+    matching_blocks::MatchingBlocks,
+    online::{self, Connect},
+    puzzle_is_over::PuzzleIsOver,
+    replay::{save_replay, Playback, Replay, LAST_REPLAY_PATH},
+    saved_game::{save_game, SAVED_GAME_PATH},
+    scoring::{high_score_path, load_high_scores_table, HighScore, HighScoresTable},
+    simulation::{Phase, SimEvent, Simulation},
+    the_pillar_descending::ThePillarIsFalling,
+    the_pillar_has_landed::ThePillarHasLanded,
+    the_rules::GameMode,
+    title_screen,
 };
 use ggez::{
-    event::{self, Button, EventHandler, KeyCode},
-    graphics::{self, DrawMode, MeshBuilder},
+    audio::{SoundData, SoundSource, Source},
+    event::{self, Button, EventHandler, GamepadId, KeyCode},
+    graphics::{self, Color, DrawMode, MeshBuilder},
     Context, GameResult,
 };
 use glam::{vec2, Vec2};
@@ -18,16 +36,35 @@ pub trait GameState {
         commands: &mut Commands,
     ) -> Option<Box<dyn GameState>>;
     fn draw(&self, ctx: &mut Context, assets: &Assets) -> GameResult;
+    /// A character typed on the keyboard, for states that take text.
+    fn text_input(&mut self, _character: char) {}
+    /// The game being played, if there is one in progress.
+    fn simulation(&self) -> Option<&Simulation> {
+        None
+    }
+    /// Escape is left to the state rather than quitting, to pause the game.
+    fn in_game(&self) -> bool {
+        self.simulation().is_some()
+    }
+    /// The state draws the scores itself, instead of the player's score and the high score.
+    fn draws_scores(&self) -> bool {
+        false
+    }
 }
 
-pub struct Commands {
+pub struct Commands<'a> {
     points_accum: u64,
     puffer: Vec<Puff>,
     current_score: u64,
-    reset_score: bool,
+    set_score: Option<u64>,
+    time_step: f32,
+    play_back: Option<Replay>,
+    playing_back: bool,
+    focus_lost: bool,
+    versus_inputs: &'a [InputState<PlayerInput, PlayerCommand>; 2],
 }
 
-impl Commands {
+impl Commands<'_> {
     pub fn add_to_score(&mut self, points: u64) {
         self.points_accum += points;
     }
@@ -41,123 +78,336 @@ impl Commands {
     }
 
     pub fn reset_score(&mut self) {
-        self.reset_score = true;
+        self.set_score(0);
+    }
+
+    /// Replace the score, when carrying on with a saved game.
+    pub fn set_score(&mut self, score: u64) {
+        self.set_score = Some(score);
+    }
+
+    /// Seconds to advance the game by this frame, taken from the recording during replays.
+    pub fn time_step(&self) -> f32 {
+        self.time_step
+    }
+
+    /// The window stopped being the one the player is using since the last frame.
+    pub fn focus_lost(&self) -> bool {
+        self.focus_lost
+    }
+
+    /// The game is being driven from a recording rather than the player's input.
+    pub fn playing_back(&self) -> bool {
+        self.playing_back
+    }
+
+    /// Drive the game from a recording, starting next frame.
+    pub fn play_back(&mut self, replay: Replay) {
+        self.play_back = Some(replay);
+    }
+
+    /// The input of each side of a versus game, one half of the keyboard or one gamepad each.
+    pub fn versus_input(&self, player: usize) -> &InputState<PlayerInput, PlayerCommand> {
+        &self.versus_inputs[player]
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct PilPos {
-    pub x: usize,
-    pub y: f32,
+/// Escape or P was pressed, or the window lost focus, so the game should stop for the pause menu.
+pub fn wants_pause(
+    input_state: &InputState<PlayerInput, PlayerCommand>,
+    commands: &Commands,
+) -> bool {
+    input_state.just_active(PlayerCommand::Pause) || commands.focus_lost()
 }
 
-impl From<Vec2> for PilPos {
-    fn from(v: Vec2) -> Self {
-        Self {
-            x: v.x as usize,
-            y: v.y,
-        }
+pub fn play_sound(
+    ctx: &mut Context,
+    assets: &Assets,
+    sound_data: &SoundData,
+    fade_in: Option<Duration>,
+) {
+    if !assets.sound_on.get() {
+        return;
+    }
+    let mut sound = Source::from_data(ctx, sound_data.clone()).unwrap();
+    sound.set_repeat(false);
+    if let Some(fade_in) = fade_in {
+        sound.set_fade_in(fade_in);
     }
+    sound.play_detached(ctx).unwrap();
 }
 
-impl From<PilPos> for Vec2 {
-    fn from(p: PilPos) -> Self {
-        vec2(p.x as f32, p.y)
+/// Advance the simulation by one frame and turn what happened into sounds, puffs and points.
+pub fn step_simulation(
+    sim: &mut Simulation,
+    ctx: &mut Context,
+    assets: &Assets,
+    input_state: &InputState<PlayerInput, PlayerCommand>,
+    commands: &mut Commands,
+) {
+    let events = sim.update(commands.time_step(), input_state);
+    present_events(sim, &events, Vec2::ZERO, ctx, assets, commands);
+    for event in events {
+        match event {
+            SimEvent::HardDropped { points, .. } => commands.add_to_score(points),
+            SimEvent::Scored(breakdown) => commands.add_to_score(breakdown.total()),
+            // a replay being watched is already saved, maybe somewhere else
+            SimEvent::GameOver if !commands.playing_back() => {
+                if let Some(replay) = &sim.replay {
+                    if let Err(e) = save_replay(LAST_REPLAY_PATH, replay) {
+                        eprintln!("couldn't save the replay: {}", e);
+                    }
+                }
+            }
+            _ => {}
+        }
     }
 }
 
-impl From<PilPos> for (usize, f32) {
-    fn from(PilPos { x, y }: PilPos) -> Self {
-        (x, y)
+/// The sounds and puffs for what happened to a board drawn `offset` from the middle of the window.
+pub fn present_events(
+    sim: &Simulation,
+    events: &[SimEvent],
+    offset: Vec2,
+    ctx: &mut Context,
+    assets: &Assets,
+    commands: &mut Commands,
+) {
+    for event in events {
+        match event {
+            SimEvent::PillarLanded | SimEvent::FloorRose => {
+                play_sound(ctx, assets, &assets.thud_sound, None)
+            }
+            SimEvent::BlocksCleared(matches) => {
+                if !matches.is_empty() {
+                    play_sound(ctx, assets, &assets.score_sound, None);
+                }
+                let tile_sz = vec2(
+                    assets.block_image.dimensions().w,
+                    assets.block_image.dimensions().h,
+                );
+                let plans = DrawingPlans::new(
+                    ctx,
+                    tile_sz,
+                    sim.common.board.sz(),
+                    sim.common.rules.pillar_spawn_pt.into(),
+                    sim.common.rules.pillar_sz,
+                    offset,
+                );
+                for &([x, y], b) in matches {
+                    let target =
+                        tile_pos(sim.common.rules.grid(), x, y as f32, tile_sz) + 0.5 * tile_sz;
+                    let color = Color {
+                        a: 0.8,
+                        ..b.color()
+                    };
+                    let mut puffer = |p| commands.add_puff(p);
+                    magic_puffs::create_puff_plosion_puff(
+                        &mut *assets.effects_rng.borrow_mut(),
+                        target + plans.board_pos,
+                        color,
+                        &mut puffer,
+                    );
+                }
+            }
+            SimEvent::GameOver => {
+                play_sound(
+                    ctx,
+                    assets,
+                    &assets.game_over_sound,
+                    Some(Duration::from_millis(400)),
+                );
+            }
+            SimEvent::HardDropped { .. }
+            | SimEvent::Scored(_)
+            | SimEvent::ChainFinished { .. }
+            | SimEvent::LevelUp(_)
+            | SimEvent::Held => {}
+        }
     }
 }
 
-pub fn pilpos(x: usize, y: f32) -> PilPos {
-    PilPos { x, y }
+/// Wrap the simulation in the game state that presents its current phase.
+pub fn present(sim: Simulation, assets: &Assets, commands: &Commands) -> Box<dyn GameState> {
+    match sim.phase {
+        Phase::Falling => Box::new(ThePillarIsFalling::new(sim)),
+        Phase::Landed { .. } => Box::new(ThePillarHasLanded { sim }),
+        Phase::Matching { .. } => Box::new(MatchingBlocks { sim }),
+        Phase::Exploding { .. } => Box::new(ExplodingBlocks { sim }),
+        Phase::GameOver { ending } if sim.common.rules.puzzle().is_some() => {
+            Box::new(PuzzleIsOver::new(sim.common, ending))
+        }
+        Phase::GameOver { ending } => Box::new(GameIsOver::new(
+            sim.common,
+            ending,
+            assets,
+            commands.get_score(),
+        )),
+    }
 }
 
-pub struct CommonState {
-    pub rules: GameRules,
-    pub board: Board,
-    pub next_pillar: Pillar,
-    pub palette: Palette,
-    pub level: u64,
-    pub puffs: Vec<Puff>,
-    // pub pillar_spawn_x: usize,
-    // pub pillar_spawn_y: f32,
-    // pub matches_required: u64,
-    //    pub pillar_sz: usize,
-    pub drop_count: u64,
-    // pub drops_per_level: u64,
-    pub pillar_fall_rate: f32,
-    // pub fall_rate_increment: f32,
-    // pub fall_rate_max: f32,
+/// Two scores in the top corners of the window, the player's and the best, or one for each side.
+/// Without a best only the player's is drawn.
+pub fn draw_scores(
+    ctx: &mut Context,
+    assets: &Assets,
+    left: u64,
+    right: Option<u64>,
+) -> GameResult {
+    let score_text = graphics::Text::new((format!("{:07}", left), assets.font, 25.0));
+    let margin = vec2(20., 20.0);
+    graphics::draw(ctx, &score_text, (margin,))?;
+    let right = match right {
+        Some(right) => right,
+        None => return Ok(()),
+    };
+    let high_score_text = graphics::Text::new((format!("{:07}", right), assets.font, 25.0));
+    let top_right = vec2(
+        graphics::screen_coordinates(ctx).right(),
+        graphics::screen_coordinates(ctx).top(),
+    );
+    let target =
+        top_right + vec2(-margin.x, margin.y) - high_score_text.width(ctx) as f32 * Vec2::X;
+    graphics::draw(ctx, &high_score_text, (target,))
 }
 
-pub fn increase_level(common: &mut CommonState) {
-    common.level += 1;
-    match common.level % 3 {
-        0 | 1 => {
-            common.pillar_fall_rate = (common.pillar_fall_rate + common.rules.fall_rate_increment)
-                .min(common.rules.max_fall_rate);
-        }
-        2 => {
-            common.palette.expand();
+/// The best score in a mode's high scores, or `None` for the modes that aren't ranked by score.
+fn best_score(assets: &Assets, mode: GameMode) -> Option<u64> {
+    let best = match mode {
+        GameMode::Marathon => assets.high_score_table.borrow().best().map(|h| h.score),
+        GameMode::TimeAttack { .. } | GameMode::RisingFloor { .. } => {
+            load_high_scores_table(high_score_path(mode))
+                .unwrap_or_else(|_| HighScoresTable::<HighScore>::new())
+                .best()
+                .map(|h| h.score)
         }
-        _ => {}
-    }
+        // sprints are ranked by time and puzzles aren't ranked at all
+        GameMode::Sprint { .. } | GameMode::Puzzle => return None,
+    };
+    Some(best.unwrap_or(0))
 }
 
 pub struct GameLoop {
     assets: Assets,
     input_state: InputState<PlayerInput, PlayerCommand>,
+    /// each side of a versus game, bound apart so neither moves the other's board
+    versus_inputs: [InputState<PlayerInput, PlayerCommand>; 2],
+    /// gamepads in the order they were first used, the first plays the left board in versus
+    gamepads: Vec<GamepadId>,
     current_score: u64,
-    high_score: u64,
+    /// the best score in the high scores of the mode being played, if it is ranked by score
+    high_score: Option<u64>,
     state: Option<Box<dyn GameState>>,
     puffs: Vec<Puff>,
+    /// a recorded game being played instead of the player's input
+    playback: Option<Playback>,
+    focus_lost: bool,
 }
 
 impl GameLoop {
-    pub fn new(ctx: &mut Context) -> GameResult<Self> {
+    /// Starts on the title screen, or getting an online game going if the command line asked.
+    pub fn new(ctx: &mut Context, connect: Option<Connect>) -> GameResult<Self> {
         let assets = Assets::load(ctx)?;
-        let high_score = assets
-            .high_score_table
-            .borrow()
-            .best()
-            .map_or(0, |h| h.score);
-        let state = title_screen::TitleScreen::new();
+        let high_score = best_score(&assets, GameMode::Marathon);
+        let state: Box<dyn GameState> = match connect {
+            Some(connect) => online::start(ctx, &assets, connect),
+            None => Box::new(title_screen::TitleScreen::new()),
+        };
+        let [left, right] = versus_input_cfgs();
         Ok(Self {
             assets,
             input_state: InputState::new(default_input_cfg()),
+            versus_inputs: [InputState::new(left), InputState::new(right)],
+            gamepads: vec![],
             current_score: 0,
             high_score,
-            state: Some(Box::new(state)),
+            state: Some(state),
             puffs: vec![],
+            playback: None,
+            focus_lost: false,
         })
     }
 }
 
+impl GameLoop {
+    /// The versus input for a gamepad, taking the first free side if it is new.
+    fn gamepad_input(
+        &mut self,
+        id: GamepadId,
+    ) -> Option<&mut InputState<PlayerInput, PlayerCommand>> {
+        let player = match self.gamepads.iter().position(|&pad| pad == id) {
+            Some(player) => player,
+            None => {
+                self.gamepads.push(id);
+                self.gamepads.len() - 1
+            }
+        };
+        self.versus_inputs.get_mut(player)
+    }
+
+    /// Keep the game in progress so it can be continued from the title screen.
+    fn save_unfinished_game(&self) {
+        let sim = self.state.as_ref().and_then(|state| state.simulation());
+        if let (Some(sim), None) = (sim, &self.playback) {
+            if !sim.is_game_over() {
+                if let Err(e) = save_game(SAVED_GAME_PATH, sim) {
+                    eprintln!("couldn't save the game: {}", e);
+                }
+            }
+        }
+    }
+}
+
 impl EventHandler for GameLoop {
     fn update(&mut self, ctx: &mut Context) -> GameResult {
+        let mut time_step = time_delta(ctx);
+        if let Some(playback) = self.playback.as_mut() {
+            // pressing start skips the rest of a replay
+            match playback.advance() {
+                Some(recorded) if !self.input_state.just_active(PlayerCommand::Start) => {
+                    time_step = recorded;
+                }
+                _ => {
+                    self.playback = None;
+                    self.state = Some(Box::new(title_screen::TitleScreen::new()));
+                }
+            }
+        }
         let mut commands = Commands {
             current_score: self.current_score,
             points_accum: 0,
             puffer: vec![],
-            reset_score: false,
+            set_score: None,
+            time_step,
+            play_back: None,
+            playing_back: self.playback.is_some(),
+            // replays can't be paused, their input can't unpause them
+            focus_lost: self.focus_lost && self.playback.is_none(),
+            versus_inputs: &self.versus_inputs,
         };
+        self.focus_lost = false;
+        let input_state = self
+            .playback
+            .as_ref()
+            .map_or(&self.input_state, |playback| &playback.input_state);
+        let was_playing = self.state.as_ref().unwrap().simulation().is_some();
         let next_state =
             self.state
                 .take()
                 .unwrap()
-                .update(ctx, &self.assets, &self.input_state, &mut commands);
+                .update(ctx, &self.assets, input_state, &mut commands);
         self.current_score += commands.points_accum;
-        if self.high_score < self.current_score {
-            self.high_score = self.current_score;
+        // a replay's points are already in the high scores, if they made it
+        if let (Some(best), None) = (&mut self.high_score, &self.playback) {
+            *best = (*best).max(self.current_score);
         }
-        if commands.reset_score {
-            self.current_score = 0;
+        if let Some(score) = commands.set_score {
+            self.current_score = score;
         }
         if let Some(s) = next_state {
+            if let (false, Some(sim)) = (was_playing, s.simulation()) {
+                self.high_score = best_score(&self.assets, sim.common.rules.mode);
+            }
             self.state = Some(s);
         } else {
             event::quit(ctx);
@@ -167,8 +417,14 @@ impl EventHandler for GameLoop {
         }
         self.puffs.extend(commands.puffer);
         self.puffs.retain(|p| 0.0 < p.life_time);
+        if let Some(replay) = commands.play_back {
+            self.playback = Some(Playback::new(replay));
+        }
 
         self.input_state.save_current();
+        for input_state in self.versus_inputs.iter_mut() {
+            input_state.save_current();
+        }
         Ok(())
     }
 
@@ -191,19 +447,9 @@ impl EventHandler for GameLoop {
             graphics::draw(ctx, &mesh, (Vec2::ZERO,))?;
         }
 
-        let score_text =
-            graphics::Text::new((format!("{:07}", self.current_score), self.assets.font, 25.0));
-        let high_score_text =
-            graphics::Text::new((format!("{:07}", self.high_score), self.assets.font, 25.0));
-        let margin = vec2(20., 20.0);
-        graphics::draw(ctx, &score_text, (margin,))?;
-        let top_right = vec2(
-            graphics::screen_coordinates(ctx).right(),
-            graphics::screen_coordinates(ctx).top(),
-        );
-        let target =
-            top_right + vec2(-margin.x, margin.y) - high_score_text.width(ctx) as f32 * Vec2::X;
-        graphics::draw(ctx, &high_score_text, (target,))?;
+        if !self.state.as_ref().unwrap().draws_scores() {
+            draw_scores(ctx, &self.assets, self.current_score, self.high_score)?;
+        }
 
         graphics::present(ctx)?;
 
@@ -217,10 +463,15 @@ impl EventHandler for GameLoop {
         _keymods: event::KeyMods,
         repeat: bool,
     ) {
-        if keycode == KeyCode::Escape {
+        let in_game = self.state.as_ref().is_some_and(|state| state.in_game());
+        if keycode == KeyCode::Escape && !in_game {
+            // in game it opens the pause menu instead
             event::quit(ctx)
         } else if !repeat {
             self.input_state.activate(PlayerInput::Key(keycode));
+            for input_state in self.versus_inputs.iter_mut() {
+                input_state.activate(PlayerInput::Key(keycode));
+            }
         }
     }
 
@@ -231,26 +482,46 @@ impl EventHandler for GameLoop {
         _keymods: event::KeyMods,
     ) {
         self.input_state.deactivate(PlayerInput::Key(keycode));
+        for input_state in self.versus_inputs.iter_mut() {
+            input_state.deactivate(PlayerInput::Key(keycode));
+        }
+    }
+
+    fn focus_event(&mut self, _ctx: &mut ggez::Context, gained: bool) {
+        if !gained {
+            self.focus_lost = true;
+        }
     }
 
-    fn text_input_event(&mut self, _ctx: &mut ggez::Context, _character: char) {}
+    fn quit_event(&mut self, _ctx: &mut ggez::Context) -> bool {
+        // the window was closed
+        self.save_unfinished_game();
+        false
+    }
+
+    fn text_input_event(&mut self, _ctx: &mut ggez::Context, character: char) {
+        if let Some(state) = self.state.as_mut() {
+            state.text_input(character);
+        }
+    }
 
     fn gamepad_button_down_event(
         &mut self,
         _ctx: &mut ggez::Context,
         button: Button,
-        _id: event::GamepadId,
+        id: GamepadId,
     ) {
         self.input_state.activate(PlayerInput::Button(button));
+        if let Some(input_state) = self.gamepad_input(id) {
+            input_state.activate(PlayerInput::Button(button));
+        }
     }
 
-    fn gamepad_button_up_event(
-        &mut self,
-        _ctx: &mut ggez::Context,
-        button: Button,
-        _id: event::GamepadId,
-    ) {
+    fn gamepad_button_up_event(&mut self, _ctx: &mut ggez::Context, button: Button, id: GamepadId) {
         self.input_state.deactivate(PlayerInput::Button(button));
+        if let Some(input_state) = self.gamepad_input(id) {
+            input_state.deactivate(PlayerInput::Button(button));
+        }
     }
 
     fn gamepad_axis_event(
@@ -0,0 +1,152 @@
+use crate::{
+    helpful_things::{neighbours, offset},
+    the_rules::Topology,
+};
+
+/// The shape of the cells on a board: which cells touch, which cells
+/// lie in a straight line, and how the columns are laid out on screen.
+/// Boards are always stored as columns, so blocks fall straight down either way.
+pub trait Grid {
+    /// Cells touching `idx` on a board of size `sz`.
+    fn neighbours(&self, idx: [usize; 2], sz: [usize; 2]) -> Vec<[usize; 2]>;
+
+    /// Number of straight lines running through each cell.
+    fn line_axes(&self) -> usize;
+
+    /// The next cell from `idx` along line `axis`, going backwards if `forward` is false.
+    fn step_along(
+        &self,
+        idx: [usize; 2],
+        axis: usize,
+        forward: bool,
+        sz: [usize; 2],
+    ) -> Option<[usize; 2]>;
+
+    /// The column `dx` steps to the side of `x`, if there is one.
+    fn horizontal_step(&self, x: usize, dx: isize, x_len: usize) -> Option<usize>;
+
+    /// How far column `x` is pushed down when drawn, in tiles.
+    fn column_shift(&self, x: usize) -> f32;
+}
+
+/// Square grids, with adjacency and wrapping set by the topology.
+impl Grid for Topology {
+    fn neighbours(&self, idx: [usize; 2], sz: [usize; 2]) -> Vec<[usize; 2]> {
+        neighbours(idx, sz, *self)
+    }
+
+    fn line_axes(&self) -> usize {
+        4
+    }
+
+    fn step_along(
+        &self,
+        idx: [usize; 2],
+        axis: usize,
+        forward: bool,
+        sz: [usize; 2],
+    ) -> Option<[usize; 2]> {
+        let [dx, dy] = [[1, 0], [0, 1], [1, 1], [1, -1]][axis];
+        let d = if forward { [dx, dy] } else { [-dx, -dy] };
+        offset(idx, d, sz, self.wraps())
+    }
+
+    fn horizontal_step(&self, x: usize, dx: isize, x_len: usize) -> Option<usize> {
+        offset([x, 0], [dx, 0], [x_len, 1], self.wraps()).map(|[x, _]| x)
+    }
+
+    fn column_shift(&self, _x: usize) -> f32 {
+        0.0
+    }
+}
+
+/// Hexagons in offset columns, with every odd column half a cell lower
+/// than its neighbours. Each cell touches six others. Ignores the topology.
+#[derive(Clone, Copy, Debug)]
+pub struct HexGrid;
+
+impl HexGrid {
+    /// Offsets to the cells on the down-right and up-right diagonals,
+    /// which depend on whether the column is shifted down.
+    fn diagonals(x: usize) -> [[isize; 2]; 2] {
+        if x.is_multiple_of(2) {
+            [[1, 0], [1, -1]]
+        } else {
+            [[1, 1], [1, 0]]
+        }
+    }
+}
+
+impl Grid for HexGrid {
+    fn neighbours(&self, idx: [usize; 2], sz: [usize; 2]) -> Vec<[usize; 2]> {
+        (0..self.line_axes())
+            .flat_map(|axis| [true, false].map(|forward| (axis, forward)))
+            .filter_map(|(axis, forward)| self.step_along(idx, axis, forward, sz))
+            .collect()
+    }
+
+    fn line_axes(&self) -> usize {
+        3
+    }
+
+    fn step_along(
+        &self,
+        idx: [usize; 2],
+        axis: usize,
+        forward: bool,
+        sz: [usize; 2],
+    ) -> Option<[usize; 2]> {
+        let d = match (axis, forward) {
+            (0, true) => [0, 1],
+            (0, false) => [0, -1],
+            (_, true) => Self::diagonals(idx[0])[axis - 1],
+            (_, false) => {
+                // going back along a diagonal lands in the column to the left,
+                // whose diagonals point back here
+                let [dx, dy] = Self::diagonals(idx[0].wrapping_sub(1))[axis - 1];
+                [-dx, -dy]
+            }
+        };
+        offset(idx, d, sz, false)
+    }
+
+    fn horizontal_step(&self, x: usize, dx: isize, x_len: usize) -> Option<usize> {
+        offset([x, 0], [dx, 0], [x_len, 1], false).map(|[x, _]| x)
+    }
+
+    fn column_shift(&self, x: usize) -> f32 {
+        if x.is_multiple_of(2) {
+            0.0
+        } else {
+            0.5
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Grid, HexGrid};
+
+    #[test]
+    fn hex_cells_have_six_neighbours_that_touch_back() {
+        let sz = [5, 6];
+        for idx in [[1, 2], [2, 2]] {
+            let ns = HexGrid.neighbours(idx, sz);
+            assert_eq!(ns.len(), 6);
+            for n in ns {
+                assert!(HexGrid.neighbours(n, sz).contains(&idx));
+            }
+        }
+    }
+
+    #[test]
+    fn hex_lines_can_be_walked_both_ways() {
+        let sz = [5, 6];
+        for axis in 0..HexGrid.line_axes() {
+            for idx in [[1, 2], [2, 2]] {
+                let next = HexGrid.step_along(idx, axis, true, sz).unwrap();
+                assert_eq!(HexGrid.step_along(next, axis, false, sz), Some(idx));
+            }
+        }
+    }
+}
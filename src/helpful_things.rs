@@ -4,6 +4,8 @@ use ggez::{graphics, Context};
 use glam::{vec2, Mat2, Vec2};
 use rand::Rng;
 
+use crate::the_rules::Topology;
+
 pub trait Center {
     fn center(&self) -> Vec2;
 }
@@ -21,17 +23,38 @@ impl Center for graphics::Rect {
 impl HalfSizeCtx for graphics::Text {
     fn half_sz(&self, ctx: &mut Context) -> Vec2 {
         let dim = self.dimensions(ctx);
-        0.5 * vec2(dim.w as f32, dim.h as f32)
+        0.5 * vec2(dim.w, dim.h)
+    }
+}
+
+/// The cell `[dx, dy]` away from `[x, y]` on a board of size `sz`,
+/// wrapping around the left and right edges if `wrap` is set.
+pub fn offset(
+    [x, y]: [usize; 2],
+    [dx, dy]: [isize; 2],
+    sz: [usize; 2],
+    wrap: bool,
+) -> Option<[usize; 2]> {
+    let (nx, ny) = (x as isize + dx, y as isize + dy);
+    if ny < 0 || sz[1] as isize <= ny {
+        return None;
     }
+    let nx = if wrap {
+        nx.rem_euclid(sz[0] as isize)
+    } else if nx < 0 || sz[0] as isize <= nx {
+        return None;
+    } else {
+        nx
+    };
+    Some([nx as usize, ny as usize])
 }
 
-pub fn neighbours([x, y]: [usize; 2]) -> [[usize; 2]; 4] {
-    [
-        [x + 1, y],
-        [x, y + 1],
-        [x.wrapping_sub(1), y],
-        [x, y.wrapping_sub(1)],
-    ]
+pub fn neighbours(idx: [usize; 2], sz: [usize; 2], topology: Topology) -> Vec<[usize; 2]> {
+    topology
+        .directions()
+        .iter()
+        .filter_map(|&d| offset(idx, d, sz, topology.wraps()))
+        .collect()
 }
 
 pub fn search<N, F>(start: N, neighbours: F) -> Vec<N>
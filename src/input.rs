@@ -2,6 +2,7 @@ use ggez::input::gamepad::gilrs::Button;
 use ggez::input::keyboard::KeyCode;
 use glam::Vec2;
 use maplit::hashmap;
+use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, collections::HashSet, hash::Hash};
 
 #[derive(Clone, Debug)]
@@ -69,17 +70,15 @@ where
     }
 
     pub fn activate(&mut self, player_input: I) {
-        self.input_cfg
-            .get(&player_input)
-            .cloned()
-            .map(|c| self.current.activate(c));
+        if let Some(&c) = self.input_cfg.get(&player_input) {
+            self.current.activate(c);
+        }
     }
 
     pub fn deactivate(&mut self, player_input: I) {
-        self.input_cfg
-            .get(&player_input)
-            .cloned()
-            .map(|c| self.current.deactivate(c));
+        if let Some(&c) = self.input_cfg.get(&player_input) {
+            self.current.deactivate(c);
+        }
     }
 
     pub fn activate_command(&mut self, player_command: C) {
@@ -98,6 +97,11 @@ where
         !self.current.contains(player_command)
     }
 
+    /// Whether the command was active before the latest changes.
+    pub fn was_active(&self, player_command: C) -> bool {
+        self.previous.contains(player_command)
+    }
+
     pub fn just_active(&self, player_command: C) -> bool {
         self.current.contains(player_command) && !self.previous.contains(player_command)
     }
@@ -113,15 +117,45 @@ pub enum PlayerInput {
     Button(ggez::input::gamepad::gilrs::Button),
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum PlayerCommand {
     MoveLeft,
     MoveRight,
     MoveDown,
+    HardDrop,
+    Hold,
     RotUp,
     RotDown,
     Start,
     Quit,
+    /// watch the last game again
+    Replay,
+    /// carry on with a saved game
+    Continue,
+    Pause,
+    /// two players side by side
+    Versus,
+    /// versus against the bot
+    VersusBot,
+    /// show where the bot would put the pillar
+    Hint,
+    /// wait for a player to join over the network
+    Host,
+    /// look for games hosted on the network
+    JoinLan,
+}
+
+impl PlayerCommand {
+    /// The commands the simulation listens to, which are all a replay needs.
+    pub const GAMEPLAY: [Self; 7] = [
+        Self::MoveLeft,
+        Self::MoveRight,
+        Self::MoveDown,
+        Self::HardDrop,
+        Self::Hold,
+        Self::RotUp,
+        Self::RotDown,
+    ];
 }
 
 pub struct InputCfg {
@@ -133,17 +167,69 @@ pub fn default_input_cfg() -> HashMap<PlayerInput, PlayerCommand> {
         PlayerInput::Key(KeyCode::Left) => PlayerCommand::MoveLeft,
         PlayerInput::Key(KeyCode::Right) => PlayerCommand::MoveRight,
         PlayerInput::Key(KeyCode::Down) => PlayerCommand::MoveDown,
+        PlayerInput::Key(KeyCode::Up) => PlayerCommand::HardDrop,
+        PlayerInput::Key(KeyCode::C) => PlayerCommand::Hold,
         PlayerInput::Key(KeyCode::Z) => PlayerCommand::RotUp,
         PlayerInput::Key(KeyCode::X) => PlayerCommand::RotDown,
         PlayerInput::Key(KeyCode::Space) => PlayerCommand::Start,
+        PlayerInput::Key(KeyCode::R) => PlayerCommand::Replay,
+        PlayerInput::Key(KeyCode::Return) => PlayerCommand::Continue,
+        PlayerInput::Key(KeyCode::P) => PlayerCommand::Pause,
+        PlayerInput::Key(KeyCode::Escape) => PlayerCommand::Pause,
+        PlayerInput::Key(KeyCode::V) => PlayerCommand::Versus,
+        PlayerInput::Key(KeyCode::B) => PlayerCommand::VersusBot,
+        PlayerInput::Key(KeyCode::Tab) => PlayerCommand::Hint,
+        PlayerInput::Key(KeyCode::H) => PlayerCommand::Host,
+        PlayerInput::Key(KeyCode::J) => PlayerCommand::JoinLan,
         PlayerInput::Button(Button::DPadLeft) => PlayerCommand::MoveLeft,
         PlayerInput::Button(Button::DPadRight) => PlayerCommand::MoveRight,
         PlayerInput::Button(Button::DPadDown) => PlayerCommand::MoveDown,
+        PlayerInput::Button(Button::North) => PlayerCommand::HardDrop,
+        PlayerInput::Button(Button::East) => PlayerCommand::Hold,
         PlayerInput::Button(Button::LeftTrigger) => PlayerCommand::RotDown,
         PlayerInput::Button(Button::RightTrigger) => PlayerCommand::RotUp,
         PlayerInput::Button(Button::West) => PlayerCommand::RotUp,
         PlayerInput::Button(Button::South) => PlayerCommand::RotDown,
         PlayerInput::Button(Button::Start) => PlayerCommand::Start,
         PlayerInput::Button(Button::Select) => PlayerCommand::Quit,
+        PlayerInput::Button(Button::Mode) => PlayerCommand::Continue,
+        PlayerInput::Button(Button::LeftThumb) => PlayerCommand::Hint,
     }
 }
+
+/// The left and right players of a versus game. Each has half the keyboard,
+/// and the gamepad buttons for whichever pad `GameLoop` hands them.
+pub fn versus_input_cfgs() -> [HashMap<PlayerInput, PlayerCommand>; 2] {
+    let gamepad = hashmap! {
+        PlayerInput::Button(Button::DPadLeft) => PlayerCommand::MoveLeft,
+        PlayerInput::Button(Button::DPadRight) => PlayerCommand::MoveRight,
+        PlayerInput::Button(Button::DPadDown) => PlayerCommand::MoveDown,
+        PlayerInput::Button(Button::North) => PlayerCommand::HardDrop,
+        PlayerInput::Button(Button::East) => PlayerCommand::Hold,
+        PlayerInput::Button(Button::LeftTrigger) => PlayerCommand::RotDown,
+        PlayerInput::Button(Button::RightTrigger) => PlayerCommand::RotUp,
+        PlayerInput::Button(Button::West) => PlayerCommand::RotUp,
+        PlayerInput::Button(Button::South) => PlayerCommand::RotDown,
+    };
+    let mut left = hashmap! {
+        PlayerInput::Key(KeyCode::A) => PlayerCommand::MoveLeft,
+        PlayerInput::Key(KeyCode::D) => PlayerCommand::MoveRight,
+        PlayerInput::Key(KeyCode::S) => PlayerCommand::MoveDown,
+        PlayerInput::Key(KeyCode::W) => PlayerCommand::HardDrop,
+        PlayerInput::Key(KeyCode::Tab) => PlayerCommand::Hold,
+        PlayerInput::Key(KeyCode::Q) => PlayerCommand::RotUp,
+        PlayerInput::Key(KeyCode::E) => PlayerCommand::RotDown,
+    };
+    let mut right = hashmap! {
+        PlayerInput::Key(KeyCode::Left) => PlayerCommand::MoveLeft,
+        PlayerInput::Key(KeyCode::Right) => PlayerCommand::MoveRight,
+        PlayerInput::Key(KeyCode::Down) => PlayerCommand::MoveDown,
+        PlayerInput::Key(KeyCode::Up) => PlayerCommand::HardDrop,
+        PlayerInput::Key(KeyCode::Slash) => PlayerCommand::Hold,
+        PlayerInput::Key(KeyCode::Comma) => PlayerCommand::RotUp,
+        PlayerInput::Key(KeyCode::Period) => PlayerCommand::RotDown,
+    };
+    left.extend(gamepad.clone());
+    right.extend(gamepad);
+    [left, right]
+}
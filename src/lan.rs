@@ -0,0 +1,325 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+};
+
+use ggez::{
+    graphics::{self, Color},
+    Context, GameResult,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::input::*;
+use crate::{
+    assets::Assets,
+    drawing::{draw_centered_text, YELLOW},
+    game_loop::{Commands, GameState},
+    online::{online_rules, Connect, Connecting},
+    timer::Timer,
+    title_screen::TitleScreen,
+};
+
+/// The port hosts listen on for players looking for a game.
+pub const DISCOVERY_PORT: u16 = 7273;
+
+/// Seconds between asking around for games.
+const ASK_EVERY: f32 = 1.0;
+
+/// Seconds a game stays listed after its host last answered.
+const FORGET_AFTER: f32 = 3.5;
+
+/// A hosted game, as told to anyone looking.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Advert {
+    pub host_name: String,
+    pub rules_name: String,
+    /// where the game itself is hosted, on the host's address
+    pub port: u16,
+    pub udp: bool,
+    /// nobody has joined yet
+    pub open: bool,
+}
+
+/// Messages between players looking for a game and the hosts on the network.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+enum Discovery {
+    /// broadcast by players looking for a game
+    Looking,
+    /// a host's answer
+    Hosting(Advert),
+}
+
+fn encode(message: &Discovery) -> io::Result<String> {
+    ron::to_string(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// This machine's name, to tell hosts apart in the list.
+pub fn host_name() -> String {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .chain(std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().chars().take(20).collect::<String>())
+        .find(|name| !name.is_empty())
+        .unwrap_or_else(|| "pillars".to_owned())
+}
+
+/// Answers players looking for a game on the network, or on this machine.
+pub struct Advertiser {
+    socket: UdpSocket,
+    pub advert: Advert,
+}
+
+impl Advertiser {
+    pub fn bind(discovery_port: u16, advert: Advert) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, discovery_port))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, advert })
+    }
+
+    pub fn discovery_port(&self) -> io::Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    /// Answer everyone who has asked since the last call.
+    pub fn poll(&self) -> io::Result<()> {
+        let mut buf = [0; 1024];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    if ron::de::from_bytes(&buf[..n]).ok() == Some(Discovery::Looking) {
+                        let answer = encode(&Discovery::Hosting(self.advert.clone()))?;
+                        self.socket.send_to(answer.as_bytes(), from)?;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                // a player that has stopped looking
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A game found on the network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LanGame {
+    /// where to join it
+    pub addr: SocketAddr,
+    pub advert: Advert,
+    /// seconds since the host last answered
+    age: f32,
+}
+
+/// Asks around for hosted games and keeps a list of the ones that answer.
+pub struct Browser {
+    socket: UdpSocket,
+    discovery_port: u16,
+    ask_timer: Timer,
+    pub games: Vec<LanGame>,
+}
+
+impl Browser {
+    pub fn new(discovery_port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_nonblocking(true)?;
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            socket,
+            discovery_port,
+            ask_timer: Timer::new(0.0),
+            games: Vec::new(),
+        })
+    }
+
+    pub fn poll(&mut self, time_delta: f32) -> io::Result<()> {
+        if self.ask_timer.update(time_delta) {
+            self.ask_timer.set(ASK_EVERY);
+            let looking = encode(&Discovery::Looking)?;
+            // asked on this machine as well, for hosts the broadcast doesn't come back to
+            for ip in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+                // without a network there is nowhere to broadcast to, which is fine
+                let _ = self
+                    .socket
+                    .send_to(looking.as_bytes(), (ip, self.discovery_port));
+            }
+        }
+        for game in self.games.iter_mut() {
+            game.age += time_delta;
+        }
+        let mut buf = [0; 1024];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    if let Ok(Discovery::Hosting(advert)) = ron::de::from_bytes(&buf[..n]) {
+                        self.found(SocketAddr::new(from.ip(), advert.port), advert);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.games.retain(|game| game.age < FORGET_AFTER);
+        Ok(())
+    }
+
+    fn found(&mut self, addr: SocketAddr, advert: Advert) {
+        match self.games.iter_mut().find(|game| game.addr == addr) {
+            Some(game) => {
+                game.advert = advert;
+                game.age = 0.0;
+            }
+            None => self.games.push(LanGame {
+                addr,
+                advert,
+                age: 0.0,
+            }),
+        }
+    }
+}
+
+/// The games hosted on the network, to pick one to join.
+pub struct JoinLan {
+    browser: Result<Browser, String>,
+    selected: usize,
+}
+
+impl JoinLan {
+    pub fn new() -> Self {
+        Self {
+            browser: Browser::new(DISCOVERY_PORT).map_err(|e| e.to_string()),
+            selected: 0,
+        }
+    }
+}
+
+impl GameState for JoinLan {
+    fn update(
+        mut self: Box<Self>,
+        _ctx: &mut Context,
+        assets: &Assets,
+        input_state: &InputState<PlayerInput, PlayerCommand>,
+        commands: &mut Commands,
+    ) -> Option<Box<dyn GameState>> {
+        if input_state.just_active(PlayerCommand::Continue) {
+            return Some(Box::new(TitleScreen::new()));
+        }
+        let browser = match &mut self.browser {
+            Ok(browser) => browser,
+            Err(_) => return Some(self),
+        };
+        if let Err(e) = browser.poll(commands.time_step()) {
+            self.browser = Err(e.to_string());
+            return Some(self);
+        }
+        let len = browser.games.len();
+        if len == 0 {
+            return Some(self);
+        }
+        if input_state.just_active(PlayerCommand::MoveDown) {
+            self.selected += 1;
+        } else if input_state.just_active(PlayerCommand::HardDrop) {
+            self.selected += len - 1;
+        }
+        self.selected %= len;
+        let game = &browser.games[self.selected];
+        if game.advert.open && input_state.just_active(PlayerCommand::Start) {
+            let connect = Connect::Join {
+                addr: game.addr,
+                udp: game.advert.udp,
+            };
+            // the host deals the rules and the seed
+            return Some(Box::new(Connecting::new(connect, online_rules(assets), 0)));
+        }
+        Some(self)
+    }
+
+    fn draw(&self, ctx: &mut Context, assets: &Assets) -> GameResult {
+        let title = graphics::Text::new(("LAN games", assets.font, 40.0));
+        draw_centered_text(ctx, &title, 100.0, Color::WHITE)?;
+        let games = match &self.browser {
+            Ok(browser) => browser.games.as_slice(),
+            Err(e) => {
+                let message = format!("couldn't look for games: {}", e);
+                let text = graphics::Text::new((message, assets.font, 18.0));
+                return draw_centered_text(ctx, &text, 180.0, Color::WHITE);
+            }
+        };
+        if games.is_empty() {
+            let text = graphics::Text::new(("looking for games...", assets.font, 20.0));
+            draw_centered_text(ctx, &text, 180.0, Color::WHITE)?;
+        }
+        let mut y = 180.0;
+        for (i, game) in games.iter().enumerate() {
+            let color = if i == self.selected {
+                YELLOW
+            } else {
+                Color::WHITE
+            };
+            let slot = if game.advert.open { "open" } else { "full" };
+            let line = format!("{}  {}", game.advert.host_name, slot);
+            let text = graphics::Text::new((line, assets.font, 25.0));
+            draw_centered_text(ctx, &text, y, color)?;
+            let rules = format!("rules {}  {}", game.advert.rules_name, game.addr);
+            let text = graphics::Text::new((rules, assets.font, 16.0));
+            draw_centered_text(ctx, &text, y + 28.0, color)?;
+            y += 60.0;
+        }
+        let y = graphics::screen_coordinates(ctx).bottom() - 60.0;
+        let join = graphics::Text::new(("start to join", assets.font, 25.0));
+        draw_centered_text(ctx, &join, y, Color::WHITE)?;
+        let back = graphics::Text::new(("enter for the title screen", assets.font, 18.0));
+        draw_centered_text(ctx, &back, y + 35.0, Color::WHITE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, thread, time::Duration};
+
+    use super::{Advert, Advertiser, Browser};
+
+    #[test]
+    fn a_game_hosted_on_this_machine_is_found() {
+        let advert = Advert {
+            host_name: "here".to_owned(),
+            rules_name: "classic".to_owned(),
+            port: 7000,
+            udp: true,
+            open: true,
+        };
+        // any free port, so a game running alongside the tests isn't bothered
+        let mut advertiser = Advertiser::bind(0, advert.clone()).unwrap();
+        let mut browser = Browser::new(advertiser.discovery_port().unwrap()).unwrap();
+        let addr: SocketAddr = ([127, 0, 0, 1], 7000).into();
+        let listed = |browser: &Browser| {
+            browser
+                .games
+                .iter()
+                .find(|game| game.addr == addr)
+                .map(|game| game.advert.clone())
+        };
+        for _ in 0..100 {
+            advertiser.poll().unwrap();
+            browser.poll(0.1).unwrap();
+            if listed(&browser).is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(listed(&browser), Some(advert));
+
+        // once someone has joined the slot is shown as taken
+        advertiser.advert.open = false;
+        for _ in 0..100 {
+            advertiser.poll().unwrap();
+            browser.poll(0.1).unwrap();
+            if listed(&browser).is_some_and(|advert| !advert.open) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(listed(&browser).map(|advert| advert.open), Some(false));
+    }
+}
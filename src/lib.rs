@@ -0,0 +1,31 @@
+#![allow(dead_code)]
+mod assets;
+mod board;
+mod bot;
+pub mod drawing;
+mod exploding_blocks;
+mod game_is_over;
+pub mod game_loop;
+mod grid;
+mod helpful_things;
+mod input;
+mod lan;
+mod magic_puffs;
+mod matching_blocks;
+pub mod online;
+mod paused;
+mod pillar;
+mod puzzle;
+mod puzzle_is_over;
+mod randomizer;
+mod replay;
+mod saved_game;
+mod scoring;
+mod simulation;
+mod the_pillar_descending;
+mod the_pillar_has_landed;
+pub mod the_rules;
+mod timer;
+mod title_screen;
+pub mod tournament;
+mod versus;
@@ -4,6 +4,7 @@ use rand::Rng;
 
 use crate::helpful_things::random_dir;
 
+#[derive(Clone)]
 pub struct Puff {
     pub pos: Vec2,
     pub vel: Vec2,
@@ -1,27 +1,10 @@
-#![allow(dead_code)]
-mod assets;
-mod board;
-mod drawing;
-mod exploding_blocks;
-mod game_is_over;
-mod game_loop;
-mod helpful_things;
-mod input;
-mod magic_puffs;
-mod matching_blocks;
-mod pillar;
-mod scoring;
-mod the_pillar_descending;
-mod the_pillar_has_landed;
-mod the_rules;
-mod timer;
-mod title_screen;
-
 use std::{env, path};
 
 use ggez::*;
+use pillars_ggez::{drawing, game_loop, online};
 
 fn main() -> GameResult {
+    let connect = online::parse_args(env::args().skip(1)).map_err(GameError::CustomError)?;
     let (mut context, event_loop) = {
         let window_setup = ggez::conf::WindowSetup {
             title: "Pillars".to_owned(),
@@ -29,8 +12,8 @@ fn main() -> GameResult {
             ..Default::default()
         };
         let window_mode = ggez::conf::WindowMode {
-            width: 320.0,
-            height: 736.0,
+            width: drawing::WINDOW_WIDTH,
+            height: drawing::WINDOW_HEIGHT,
             ..Default::default()
         };
         let context_builder = ContextBuilder::new("Pillars", ":/")
@@ -45,6 +28,6 @@ fn main() -> GameResult {
         .build()?
     };
 
-    let event_handler = game_loop::GameLoop::new(&mut context)?;
+    let event_handler = game_loop::GameLoop::new(&mut context, connect)?;
     event::run(context, event_loop, event_handler)
 }
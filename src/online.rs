@@ -0,0 +1,1166 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    rc::Rc,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use ggez::{
+    graphics::{self, Color},
+    Context, GameResult,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::input::*;
+use crate::{
+    assets::Assets,
+    drawing::{draw_centered_text, WINDOW_WIDTH},
+    game_loop::{present_events, Commands, GameState},
+    helpful_things::time_delta,
+    lan::{host_name, Advert, Advertiser, DISCOVERY_PORT},
+    simulation::{effects_rng, link_rng, GameRng, SimEvent, Simulation},
+    the_rules::{GameMode, GameRules},
+    timer::Timer,
+    versus::{
+        back_to_title, board_offset, draw_banner, draw_boards, outcome, set_window_width,
+        step_both, Outcome,
+    },
+};
+
+/// The port games are hosted on unless another is given.
+pub const DEFAULT_PORT: u16 = 7272;
+
+/// Every online game is played in steps of this many seconds, so both ends
+/// run the same frames however fast their screens are.
+pub const NET_STEP: f32 = 1.0 / 60.0;
+
+/// How many frames ahead of the other player's known input a peer may guess
+/// before it waits for them to catch up.
+pub const MAX_ROLLBACK: usize = 15;
+
+/// Seconds without a word from the other end before giving up on them.
+const TIMEOUT: f32 = 5.0;
+
+/// Messages between the two ends of an online game.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Packet {
+    /// a client asking to play, repeated until the host starts the game
+    Hello,
+    /// the host dealing a match, the client plays the right board
+    Start {
+        round: u32,
+        seed: u64,
+        rules: Box<GameRules>,
+    },
+    /// the sender's commands from `first_frame` on that haven't been acknowledged,
+    /// and how many frames of the receiver's commands it has
+    Inputs {
+        round: u32,
+        first_frame: usize,
+        commands: Vec<u8>,
+        received: usize,
+    },
+    /// the sender giving up the match and leaving
+    Resign { round: u32 },
+}
+
+fn encode(packet: &Packet) -> io::Result<String> {
+    ron::to_string(packet).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Packet> {
+    ron::de::from_bytes(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// A way of getting packets to the other player and back, which never waits.
+pub trait Transport {
+    fn send(&mut self, packet: &Packet) -> io::Result<()>;
+    /// Everything that has arrived since the last call.
+    fn receive(&mut self) -> io::Result<Vec<Packet>>;
+}
+
+/// Packets over TCP, one line of RON each.
+pub struct TcpTransport {
+    stream: TcpStream,
+    received: Vec<u8>,
+    /// what the socket wouldn't take yet
+    unsent: Vec<u8>,
+}
+
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            received: vec![],
+            unsent: vec![],
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, packet: &Packet) -> io::Result<()> {
+        self.unsent.extend(encode(packet)?.bytes());
+        self.unsent.push(b'\n');
+        while !self.unsent.is_empty() {
+            match self.stream.write(&self.unsent) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.unsent.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<Packet>> {
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::ConnectionAborted.into()),
+                Ok(n) => self.received.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut packets = vec![];
+        while let Some(end) = self.received.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.received.drain(..=end).collect();
+            packets.push(decode(&line[..end])?);
+        }
+        Ok(packets)
+    }
+}
+
+/// Packets over UDP, one datagram each. Lost ones are made up for by
+/// everything unacknowledged being sent again.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// `socket` should already be connected to the other end.
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+}
+
+/// Errors that only mean nothing has arrived, or the other end isn't listening yet.
+fn is_quiet(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionRefused
+    )
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &Packet) -> io::Result<()> {
+        match self.socket.send(encode(packet)?.as_bytes()) {
+            Err(e) if !is_quiet(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<Packet>> {
+        let mut buf = [0; 65536];
+        let mut packets = vec![];
+        loop {
+            match self.socket.recv(&mut buf) {
+                // anything that isn't one of ours is ignored
+                Ok(n) => packets.extend(decode(&buf[..n]).ok()),
+                Err(e) if is_quiet(&e) => return Ok(packets),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+struct Link {
+    /// packets on their way to each end, with the receive they turn up on
+    queues: [Vec<(u64, Packet)>; 2],
+    receives: [u64; 2],
+    latency: u64,
+    loss: f64,
+    rng: GameRng,
+}
+
+/// One end of an in-process link, for trying out the netcode without a network.
+/// Packets are held back for `latency` calls to `receive` and some go missing.
+pub struct LoopbackTransport {
+    end: usize,
+    link: Rc<RefCell<Link>>,
+}
+
+/// Two ends joined up, losing packets at the `loss` rate, picked by `seed`.
+pub fn loopback_pair(latency: u64, loss: f64, seed: u64) -> [LoopbackTransport; 2] {
+    let link = Rc::new(RefCell::new(Link {
+        queues: [vec![], vec![]],
+        receives: [0, 0],
+        latency,
+        loss,
+        rng: link_rng(seed),
+    }));
+    [0, 1].map(|end| LoopbackTransport {
+        end,
+        link: link.clone(),
+    })
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, packet: &Packet) -> io::Result<()> {
+        let link = &mut *self.link.borrow_mut();
+        let other = 1 - self.end;
+        if !link.rng.gen_bool(link.loss) {
+            let due = link.receives[other] + link.latency;
+            link.queues[other].push((due, packet.clone()));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<Packet>> {
+        let link = &mut *self.link.borrow_mut();
+        link.receives[self.end] += 1;
+        let now = link.receives[self.end];
+        let (arrived, waiting) = std::mem::take(&mut link.queues[self.end])
+            .into_iter()
+            .partition(|&(due, _)| due <= now);
+        link.queues[self.end] = waiting;
+        Ok(arrived.into_iter().map(|(_, packet)| packet).collect())
+    }
+}
+
+/// The gameplay commands held on a frame, a bit each, which is all that is sent of them.
+pub fn pack_commands(input_state: &InputState<PlayerInput, PlayerCommand>) -> u8 {
+    PlayerCommand::GAMEPLAY
+        .iter()
+        .enumerate()
+        .filter(|&(_, &command)| input_state.active(command))
+        .fold(0, |bits, (i, _)| bits | 1 << i)
+}
+
+/// The input for a frame, from the commands held on it and on the one before.
+fn unpack_commands(previous: u8, current: u8) -> InputState<PlayerInput, PlayerCommand> {
+    let mut input_state = InputState::new(HashMap::new());
+    let set = |input_state: &mut InputState<_, _>, bits: u8| {
+        for (i, &command) in PlayerCommand::GAMEPLAY.iter().enumerate() {
+            if bits & 1 << i != 0 {
+                input_state.activate_command(command);
+            } else {
+                input_state.deactivate_command(command);
+            }
+        }
+    };
+    set(&mut input_state, previous);
+    input_state.save_current();
+    set(&mut input_state, current);
+    input_state
+}
+
+/// Both boards of an online match. Each end only knows its own commands straight away,
+/// so it plays on guessing the other player still holds what they last did,
+/// and plays the frames again from the last sure one whenever their commands turn up.
+pub struct Rollback {
+    /// the side played here, the host plays the left
+    pub local: usize,
+    /// the boards as of `confirmed_frame`, which nothing late can change
+    confirmed: [Simulation; 2],
+    confirmed_frame: usize,
+    /// the commands held by each side on every frame so far
+    inputs: [Vec<u8>; 2],
+    /// the boards at the latest frame
+    pub predicted: [Simulation; 2],
+}
+
+impl Rollback {
+    /// Both ends build the same boards, and deal the same pillars, from the host's seed.
+    pub fn new(local: usize, rules: GameRules, seed: u64) -> Self {
+        let sims = [
+            Simulation::new_game(rules.clone(), seed),
+            Simulation::new_game(rules, seed),
+        ];
+        Self {
+            local,
+            confirmed: sims.clone(),
+            confirmed_frame: 0,
+            inputs: [vec![], vec![]],
+            predicted: sims,
+        }
+    }
+
+    /// The next frame to be played here.
+    pub fn frame(&self) -> usize {
+        self.inputs[self.local].len()
+    }
+
+    /// Frames of the other side's commands that have arrived.
+    pub fn remote_frames(&self) -> usize {
+        self.inputs[1 - self.local].len()
+    }
+
+    /// Whether the local side is few enough frames ahead to play another.
+    pub fn can_advance(&self) -> bool {
+        self.frame() - self.confirmed_frame < MAX_ROLLBACK
+    }
+
+    /// How the match went, once it is sure.
+    pub fn outcome(&self) -> Option<Outcome> {
+        outcome(&self.confirmed)
+    }
+
+    /// The local commands from `first_frame` on.
+    pub fn local_commands(&self, first_frame: usize) -> &[u8] {
+        &self.inputs[self.local][first_frame.min(self.frame())..]
+    }
+
+    /// Take in the other side's commands from `first_frame` on, some of which may be old news.
+    pub fn receive(&mut self, first_frame: usize, commands: &[u8]) {
+        let remote = &mut self.inputs[1 - self.local];
+        if remote.len() < first_frame {
+            // there is a gap, wait for them to be sent again
+            return;
+        }
+        let known = remote.len() - first_frame;
+        remote.extend(commands.iter().skip(known));
+        self.confirm();
+    }
+
+    /// Play the next frame holding `commands`,
+    /// returning what happened on it as far as can be told.
+    pub fn advance(&mut self, commands: u8) -> [Vec<SimEvent>; 2] {
+        self.inputs[self.local].push(commands);
+        let mut events = self.confirm();
+        self.predicted = self.confirmed.clone();
+        for frame in self.confirmed_frame..self.frame() {
+            events = Some(step_frame(&mut self.predicted, &self.inputs, frame));
+        }
+        events.unwrap_or_default()
+    }
+
+    /// Play the confirmed boards on through every frame both sides' commands are in for,
+    /// returning what happened on the latest frame if that was one of them.
+    fn confirm(&mut self) -> Option<[Vec<SimEvent>; 2]> {
+        let known = self.inputs[0].len().min(self.inputs[1].len());
+        let mut events = None;
+        while self.confirmed_frame < known {
+            let frame_events = step_frame(&mut self.confirmed, &self.inputs, self.confirmed_frame);
+            self.confirmed_frame += 1;
+            if self.confirmed_frame == self.frame() {
+                events = Some(frame_events);
+            }
+        }
+        events
+    }
+}
+
+/// Play one frame, guessing any commands that haven't arrived are the last ones that did.
+fn step_frame(
+    sims: &mut [Simulation; 2],
+    inputs: &[Vec<u8>; 2],
+    frame: usize,
+) -> [Vec<SimEvent>; 2] {
+    let held = |player: usize, frame: usize| {
+        let commands = &inputs[player];
+        commands
+            .get(frame)
+            .or_else(|| commands.last())
+            .copied()
+            .unwrap_or(0)
+    };
+    let input = |player| {
+        let previous = frame.checked_sub(1).map_or(0, |f| held(player, f));
+        unpack_commands(previous, held(player, frame))
+    };
+    let inputs = [input(0), input(1)];
+    step_both(sims, [&inputs[0], &inputs[1]], NET_STEP)
+}
+
+/// One end of an online match and the way to the other.
+pub struct Peer {
+    transport: Box<dyn Transport>,
+    pub session: Rollback,
+    round: u32,
+    /// set by the host, so it can deal the match again to a client that missed it
+    start: Option<Packet>,
+    /// frames of local commands the other end has
+    acked: usize,
+    /// seconds since the other end was heard from
+    silence: f32,
+    /// the other end resigned and has gone
+    pub left: bool,
+}
+
+impl Peer {
+    pub fn host(transport: Box<dyn Transport>, rules: GameRules, seed: u64) -> Self {
+        let start = Packet::Start {
+            round: 0,
+            seed,
+            rules: Box::new(rules.clone()),
+        };
+        Self {
+            transport,
+            session: Rollback::new(0, rules, seed),
+            round: 0,
+            start: Some(start),
+            acked: 0,
+            silence: 0.0,
+            left: false,
+        }
+    }
+
+    pub fn client(transport: Box<dyn Transport>, rules: GameRules, seed: u64, round: u32) -> Self {
+        Self {
+            transport,
+            session: Rollback::new(1, rules, seed),
+            round,
+            start: None,
+            acked: 0,
+            silence: 0.0,
+            left: false,
+        }
+    }
+
+    /// Deal a new match from the host's end.
+    pub fn rematch(&mut self, seed: u64) -> io::Result<()> {
+        let rules = self.session.predicted[0].common.rules.clone();
+        self.round += 1;
+        let start = Packet::Start {
+            round: self.round,
+            seed,
+            rules: Box::new(rules.clone()),
+        };
+        self.transport.send(&start)?;
+        self.start = Some(start);
+        self.session = Rollback::new(0, rules, seed);
+        self.acked = 0;
+        Ok(())
+    }
+
+    /// Give up the match before leaving it. If the packet goes missing
+    /// the other end only sees the connection go quiet.
+    pub fn resign(&mut self) -> io::Result<()> {
+        self.transport.send(&Packet::Resign { round: self.round })
+    }
+
+    /// Take in whatever the other end has sent.
+    /// Returns true if they dealt a new match.
+    pub fn poll(&mut self, time_delta: f32) -> io::Result<bool> {
+        let packets = self.transport.receive()?;
+        self.silence = if packets.is_empty() {
+            self.silence + time_delta
+        } else {
+            0.0
+        };
+        if TIMEOUT < self.silence {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let mut restarted = false;
+        for packet in packets {
+            match packet {
+                Packet::Hello => {
+                    if let Some(start) = &self.start {
+                        self.transport.send(start)?;
+                    }
+                }
+                Packet::Start { round, seed, rules } if self.round < round => {
+                    rules
+                        .validate()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    self.round = round;
+                    self.session = Rollback::new(1, *rules, seed);
+                    self.acked = 0;
+                    restarted = true;
+                }
+                Packet::Inputs {
+                    round,
+                    first_frame,
+                    commands,
+                    received,
+                } if round == self.round => {
+                    self.session.receive(first_frame, &commands);
+                    self.acked = self.acked.max(received);
+                }
+                Packet::Resign { round } if round == self.round => self.left = true,
+                _ => {}
+            }
+        }
+        Ok(restarted)
+    }
+
+    /// Play the next frame unless the match is over or too far ahead of the other end.
+    pub fn step(&mut self, commands: u8) -> Option<[Vec<SimEvent>; 2]> {
+        if self.session.outcome().is_some() || !self.session.can_advance() {
+            return None;
+        }
+        Some(self.session.advance(commands))
+    }
+
+    /// Send the other end every local command they haven't acknowledged.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.transport.send(&Packet::Inputs {
+            round: self.round,
+            first_frame: self.acked,
+            commands: self.session.local_commands(self.acked).to_vec(),
+            received: self.session.remote_frames(),
+        })
+    }
+}
+
+/// How to get an online game going, from the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Connect {
+    Host {
+        port: u16,
+        udp: bool,
+    },
+    Join {
+        addr: SocketAddr,
+        udp: bool,
+    },
+    /// both ends in this window, over a slow link that loses packets
+    Loopback {
+        latency: u64,
+        loss: f64,
+    },
+}
+
+pub const USAGE: &str =
+    "usage: pillars [--host [PORT] | --join ADDRESS[:PORT] | --loopback] [--udp]";
+
+/// Read `--host`, `--join` or `--loopback`, and `--udp`, from the command line.
+pub fn parse_args<I: Iterator<Item = String>>(args: I) -> Result<Option<Connect>, String> {
+    let args: Vec<String> = args.collect();
+    let udp = args.iter().any(|arg| arg == "--udp");
+    let value = |i: usize| args.get(i + 1).filter(|value| !value.starts_with("--"));
+    let mut connect = None;
+    for (i, arg) in args.iter().enumerate() {
+        connect = match arg.as_str() {
+            "--host" => {
+                let port = match value(i) {
+                    Some(port) => port.parse().map_err(|_| USAGE.to_owned())?,
+                    None => DEFAULT_PORT,
+                };
+                Some(Connect::Host { port, udp })
+            }
+            "--join" => {
+                let addr = value(i).ok_or_else(|| USAGE.to_owned())?;
+                let addr = if addr.contains(':') {
+                    addr.to_owned()
+                } else {
+                    format!("{}:{}", addr, DEFAULT_PORT)
+                };
+                let addr = addr.parse().map_err(|_| USAGE.to_owned())?;
+                Some(Connect::Join { addr, udp })
+            }
+            "--loopback" => Some(Connect::Loopback {
+                latency: 6,
+                loss: 0.1,
+            }),
+            "--udp" => connect,
+            _ if args[..i]
+                .last()
+                .is_some_and(|prev| prev == "--host" || prev == "--join") =>
+            {
+                connect
+            }
+            _ => return Err(USAGE.to_owned()),
+        };
+    }
+    Ok(connect)
+}
+
+/// Online games are marathons by the first ruleset, dealt by the host.
+pub fn online_rules(assets: &Assets) -> GameRules {
+    GameRules {
+        mode: GameMode::Marathon,
+        ..assets.rule_sets[0].clone()
+    }
+}
+
+/// The state to start in for the command line's `connect`.
+pub fn start(ctx: &mut Context, assets: &Assets, connect: Connect) -> Box<dyn GameState> {
+    let rules = online_rules(assets);
+    let seed = rand::random::<u64>();
+    match connect {
+        Connect::Loopback { latency, loss } => {
+            *assets.effects_rng.borrow_mut() = effects_rng(seed);
+            Box::new(OnlineVersus::loopback(ctx, rules, seed, latency, loss))
+        }
+        connect => Box::new(Connecting::new(connect, rules, seed)),
+    }
+}
+
+enum Waiting {
+    Listening(TcpListener),
+    Dialing(Receiver<io::Result<TcpStream>>),
+    /// a host's UDP socket, not yet connected to anyone
+    Open(UdpSocket),
+    Connected(Box<dyn Transport>),
+    Failed(String),
+}
+
+/// Waiting for the other player, before an online match.
+pub struct Connecting {
+    connect: Connect,
+    waiting: Waiting,
+    rules: GameRules,
+    seed: u64,
+    hello_timer: Timer,
+    /// tells players on the network about a hosted game
+    advertiser: Option<Advertiser>,
+}
+
+impl Connecting {
+    pub fn new(connect: Connect, rules: GameRules, seed: u64) -> Self {
+        let waiting = match &connect {
+            Connect::Host { port, udp: false } => {
+                TcpListener::bind(("0.0.0.0", *port)).and_then(|listener| {
+                    listener.set_nonblocking(true)?;
+                    Ok(Waiting::Listening(listener))
+                })
+            }
+            Connect::Host { port, udp: true } => {
+                UdpSocket::bind(("0.0.0.0", *port)).and_then(|socket| {
+                    socket.set_nonblocking(true)?;
+                    Ok(Waiting::Open(socket))
+                })
+            }
+            &Connect::Join { addr, udp: false } => {
+                let (sender, receiver) = mpsc::channel();
+                thread::spawn(move || sender.send(TcpStream::connect(addr)));
+                Ok(Waiting::Dialing(receiver))
+            }
+            &Connect::Join { addr, udp: true } => {
+                UdpSocket::bind(("0.0.0.0", 0)).and_then(|socket| {
+                    socket.connect(addr)?;
+                    let transport: Box<dyn Transport> = Box::new(UdpTransport::new(socket)?);
+                    Ok(Waiting::Connected(transport))
+                })
+            }
+            Connect::Loopback { .. } => Err(io::ErrorKind::Unsupported.into()),
+        };
+        let advertiser = match connect {
+            Connect::Host { port, udp } => {
+                let advert = Advert {
+                    host_name: host_name(),
+                    rules_name: rules.name.clone(),
+                    port,
+                    udp,
+                    open: true,
+                };
+                // a second host on one machine can still be joined by address
+                Advertiser::bind(DISCOVERY_PORT, advert)
+                    .map_err(|e| eprintln!("couldn't advertise the game: {}", e))
+                    .ok()
+            }
+            _ => None,
+        };
+        Self {
+            connect,
+            waiting: waiting.unwrap_or_else(|e| Waiting::Failed(e.to_string())),
+            rules,
+            seed,
+            hello_timer: Timer::new(0.0),
+            advertiser,
+        }
+    }
+
+    fn is_host(&self) -> bool {
+        matches!(self.connect, Connect::Host { .. })
+    }
+
+    /// Move on as far as possible, returning the packet that starts the match once it arrives.
+    fn wait(&mut self, time_delta: f32) -> io::Result<Option<Packet>> {
+        if let Some(advertiser) = &self.advertiser {
+            advertiser.poll()?;
+        }
+        let waiting = std::mem::replace(&mut self.waiting, Waiting::Failed(String::new()));
+        self.waiting = match waiting {
+            Waiting::Listening(listener) => match listener.accept() {
+                Ok((stream, _)) => Waiting::Connected(Box::new(TcpTransport::new(stream)?)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => Waiting::Listening(listener),
+                Err(e) => return Err(e),
+            },
+            Waiting::Dialing(receiver) => match receiver.try_recv() {
+                Ok(stream) => Waiting::Connected(Box::new(TcpTransport::new(stream?)?)),
+                Err(_) => Waiting::Dialing(receiver),
+            },
+            Waiting::Open(socket) => {
+                let mut buf = [0; 65536];
+                match socket.recv_from(&mut buf) {
+                    Ok((n, from)) if decode(&buf[..n]).ok() == Some(Packet::Hello) => {
+                        // the client says hello again until it is answered
+                        socket.connect(from)?;
+                        Waiting::Connected(Box::new(UdpTransport::new(socket)?))
+                    }
+                    Ok(_) => Waiting::Open(socket),
+                    Err(e) if is_quiet(&e) => Waiting::Open(socket),
+                    Err(e) => return Err(e),
+                }
+            }
+            waiting => waiting,
+        };
+        let host = self.is_host();
+        let transport = match &mut self.waiting {
+            Waiting::Connected(transport) => transport,
+            _ => return Ok(None),
+        };
+        if !host && self.hello_timer.update(time_delta) {
+            transport.send(&Packet::Hello)?;
+            self.hello_timer.set(0.5);
+        }
+        let packets = transport.receive()?;
+        Ok(packets.into_iter().find(|packet| match packet {
+            Packet::Hello => host,
+            Packet::Start { .. } => !host,
+            Packet::Inputs { .. } | Packet::Resign { .. } => false,
+        }))
+    }
+}
+
+impl GameState for Connecting {
+    fn update(
+        mut self: Box<Self>,
+        ctx: &mut Context,
+        assets: &Assets,
+        input_state: &InputState<PlayerInput, PlayerCommand>,
+        _commands: &mut Commands,
+    ) -> Option<Box<dyn GameState>> {
+        if input_state.just_active(PlayerCommand::Continue) {
+            return Some(back_to_title(ctx));
+        }
+        let arrived = match self.wait(time_delta(ctx)) {
+            Ok(arrived) => arrived,
+            Err(e) => {
+                self.waiting = Waiting::Failed(e.to_string());
+                None
+            }
+        };
+        let transport = match (arrived, self.waiting) {
+            (Some(packet), Waiting::Connected(transport)) => (packet, transport),
+            (_, waiting) => {
+                self.waiting = waiting;
+                return Some(self);
+            }
+        };
+        if let (Packet::Start { rules, .. }, _) = &transport {
+            if let Err(e) = rules.validate() {
+                self.waiting = Waiting::Failed(format!("the host's rules can't be played, {}", e));
+                return Some(self);
+            }
+        }
+        let peer = match transport {
+            (Packet::Hello, transport) => {
+                *assets.effects_rng.borrow_mut() = effects_rng(self.seed);
+                let mut peer = Peer::host(transport, self.rules, self.seed);
+                if let Some(start) = peer.start.clone() {
+                    if let Err(e) = peer.transport.send(&start) {
+                        eprintln!("couldn't start the game: {}", e);
+                    }
+                }
+                peer
+            }
+            (Packet::Start { round, seed, rules }, transport) => {
+                *assets.effects_rng.borrow_mut() = effects_rng(seed);
+                Peer::client(transport, *rules, seed, round)
+            }
+            (Packet::Inputs { .. } | Packet::Resign { .. }, _) => {
+                unreachable!("only hellos and starts are waited for")
+            }
+        };
+        let advertiser = self.advertiser.map(|mut advertiser| {
+            advertiser.advert.open = false;
+            advertiser
+        });
+        Some(Box::new(OnlineVersus::new(ctx, peer, None, advertiser)))
+    }
+
+    fn draw(&self, ctx: &mut Context, assets: &Assets) -> GameResult {
+        let message = match (&self.waiting, &self.connect) {
+            (Waiting::Failed(e), _) => format!("couldn't connect: {}", e),
+            (_, Connect::Host { port, .. }) => format!("waiting for a player on port {}", port),
+            (_, Connect::Join { addr, .. }) => format!("joining {}", addr),
+            (_, Connect::Loopback { .. }) => String::new(),
+        };
+        let y = graphics::screen_coordinates(ctx).center().y;
+        let text = graphics::Text::new((message, assets.font, 20.0));
+        draw_centered_text(ctx, &text, y, Color::WHITE)?;
+        let back = graphics::Text::new(("enter for the title screen", assets.font, 18.0));
+        draw_centered_text(ctx, &back, y + 40.0, Color::WHITE)?;
+        if let Some(advertiser) = &self.advertiser {
+            let name = format!("listed on the LAN as {}", advertiser.advert.host_name);
+            let text = graphics::Text::new((name, assets.font, 18.0));
+            draw_centered_text(ctx, &text, y + 70.0, Color::WHITE)?;
+        }
+        Ok(())
+    }
+}
+
+/// Versus against a player on the other end of a connection.
+pub struct OnlineVersus {
+    peer: Peer,
+    /// the other end of a loopback link, played from the right half of the keyboard
+    loopback: Option<Peer>,
+    wins: [u64; 2],
+    /// seconds of play not yet made into a frame
+    unplayed: f32,
+    lost: Option<String>,
+    /// asked to leave in the middle of a match, waiting to be sure
+    quitting: bool,
+    fade: f32,
+    /// still answers players looking for games, to show them this one is full
+    advertiser: Option<Advertiser>,
+}
+
+impl OnlineVersus {
+    pub fn new(
+        ctx: &mut Context,
+        peer: Peer,
+        loopback: Option<Peer>,
+        advertiser: Option<Advertiser>,
+    ) -> Self {
+        set_window_width(ctx, 2.0 * WINDOW_WIDTH);
+        Self {
+            peer,
+            loopback,
+            wins: [0, 0],
+            unplayed: 0.0,
+            lost: None,
+            quitting: false,
+            fade: 0.0,
+            advertiser,
+        }
+    }
+
+    /// Both ends in one window, `latency` frames apart and losing a share `loss` of packets.
+    pub fn loopback(
+        ctx: &mut Context,
+        rules: GameRules,
+        seed: u64,
+        latency: u64,
+        loss: f64,
+    ) -> Self {
+        let [host, client] = loopback_pair(latency, loss, seed);
+        let host = Peer::host(Box::new(host), rules.clone(), seed);
+        let client = Peer::client(Box::new(client), rules, seed, 0);
+        Self::new(ctx, host, Some(client), None)
+    }
+
+    fn local(&self) -> usize {
+        self.peer.session.local
+    }
+
+    /// Play as many frames as have gone by, keeping in touch with the other end.
+    fn play(
+        &mut self,
+        ctx: &mut Context,
+        assets: &Assets,
+        input_state: &InputState<PlayerInput, PlayerCommand>,
+        commands: &mut Commands,
+    ) -> io::Result<()> {
+        let time_step = commands.time_step();
+        if let Some(advertiser) = &self.advertiser {
+            advertiser.poll()?;
+        }
+        if self.peer.poll(time_step)? {
+            self.fade = 0.0;
+        }
+        if let Some(other) = &mut self.loopback {
+            other.poll(time_step)?;
+        }
+        // a slow frame is caught up on, but not without limit
+        self.unplayed = (self.unplayed + time_step).min(4.0 * NET_STEP);
+        let local_commands = match self.loopback {
+            Some(_) => pack_commands(commands.versus_input(0)),
+            None => pack_commands(input_state),
+        };
+        while NET_STEP <= self.unplayed {
+            self.unplayed -= NET_STEP;
+            if let Some(events) = self.peer.step(local_commands) {
+                for (player, events) in events.iter().enumerate() {
+                    let sim = &self.peer.session.predicted[player];
+                    present_events(sim, events, board_offset(player), ctx, assets, commands);
+                }
+            }
+            if let Some(other) = &mut self.loopback {
+                other.step(pack_commands(commands.versus_input(1)));
+            }
+        }
+        self.peer.flush()?;
+        if let Some(other) = &mut self.loopback {
+            other.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl GameState for OnlineVersus {
+    fn update(
+        mut self: Box<Self>,
+        ctx: &mut Context,
+        assets: &Assets,
+        input_state: &InputState<PlayerInput, PlayerCommand>,
+        commands: &mut Commands,
+    ) -> Option<Box<dyn GameState>> {
+        let over = self.peer.session.outcome().is_some();
+        let finished = over || self.peer.left || self.lost.is_some();
+        let leave = input_state.just_active(PlayerCommand::Continue);
+        if finished && (leave || input_state.just_active(PlayerCommand::Pause)) {
+            return Some(back_to_title(ctx));
+        }
+        if self.quitting && leave {
+            if let Err(e) = self.peer.resign() {
+                eprintln!("couldn't tell the other player: {}", e);
+            }
+            return Some(back_to_title(ctx));
+        }
+        // the match can't stop for it, so leaving halfway is asked about first
+        if leave || input_state.just_active(PlayerCommand::Pause) {
+            self.quitting = !self.quitting || leave;
+        }
+        if self.lost.is_some() || self.peer.left {
+            return Some(self);
+        }
+        if let Err(e) = self.play(ctx, assets, input_state, commands) {
+            self.lost = Some(e.to_string());
+            return Some(self);
+        }
+        if self.peer.left {
+            self.quitting = false;
+            if !over {
+                self.wins[self.local()] += 1;
+            }
+            return Some(self);
+        }
+        if self.peer.session.outcome().is_some() {
+            self.quitting = false;
+        }
+        match self.peer.session.outcome() {
+            Some(Outcome::Won(player)) if !over => self.wins[player] += 1,
+            Some(_) => self.fade = (self.fade + 0.5 * time_delta(ctx)).min(0.9),
+            None => {}
+        }
+        // the host deals the rematches
+        if over && self.local() == 0 && input_state.just_active(PlayerCommand::Start) {
+            let seed = rand::random::<u64>();
+            *assets.effects_rng.borrow_mut() = effects_rng(seed);
+            if let Err(e) = self.peer.rematch(seed) {
+                self.lost = Some(e.to_string());
+            }
+            self.fade = 0.0;
+        }
+        Some(self)
+    }
+
+    fn draw(&self, ctx: &mut Context, assets: &Assets) -> GameResult {
+        draw_boards(ctx, assets, &self.peer.session.predicted)?;
+        let local = self.local();
+        let [left, right] = self.wins;
+        let score = format!("{} - {}", left, right);
+        let back = "enter for the title screen".to_owned();
+        if let Some(e) = &self.lost {
+            let lines = [e.clone(), back];
+            return draw_banner(ctx, assets, "CONNECTION LOST", &lines, 0.7, false);
+        }
+        if self.peer.left {
+            let lines = [score, "the other player left".to_owned(), back];
+            return draw_banner(ctx, assets, "YOU WIN", &lines, 0.7, true);
+        }
+        let headline = match self.peer.session.outcome() {
+            Some(Outcome::Won(player)) if player == local => "YOU WIN",
+            Some(Outcome::Won(_)) => "YOU LOSE",
+            Some(Outcome::Draw) => "DRAW",
+            None if self.quitting => {
+                let lines = ["enter to resign".to_owned(), "escape to play on".to_owned()];
+                return draw_banner(ctx, assets, "LEAVE THE MATCH?", &lines, 0.5, false);
+            }
+            None => return Ok(()),
+        };
+        let next = if local == 0 {
+            "start for a rematch"
+        } else {
+            "waiting for a rematch"
+        };
+        let lines = [score, next.to_owned(), back];
+        draw_banner(ctx, assets, headline, &lines, self.fade, true)
+    }
+
+    fn in_game(&self) -> bool {
+        true
+    }
+
+    fn draws_scores(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use super::{
+        loopback_pair, parse_args, step_frame, Connect, Packet, Peer, Rollback, TcpTransport,
+        Transport, DEFAULT_PORT,
+    };
+    use crate::simulation::Simulation;
+    use crate::the_rules::GameRules;
+
+    /// Made up play, different for each side: moving about and hard dropping now and then.
+    fn scripted(player: usize, frame: usize) -> u8 {
+        let mut bits = 0;
+        if frame % (23 + 7 * player) < 2 {
+            bits |= 1 << 3;
+        }
+        if (frame / 40 + player).is_multiple_of(3) {
+            bits |= 1 << 0;
+        } else if (frame / 40 + player) % 3 == 1 {
+            bits |= 1 << 1;
+        }
+        if frame.is_multiple_of(17) {
+            bits |= 1 << 5;
+        }
+        bits
+    }
+
+    #[test]
+    fn a_resignation_reaches_the_other_end() {
+        let rules = GameRules::default();
+        let [host, client] = loopback_pair(2, 0.0, 5);
+        let mut host = Peer::host(Box::new(host), rules.clone(), 11);
+        let mut client = Peer::client(Box::new(client), rules, 11, 0);
+        client.resign().unwrap();
+        for _ in 0..5 {
+            host.poll(1.0 / 60.0).unwrap();
+        }
+        assert!(host.left);
+        assert!(!client.left);
+    }
+
+    #[test]
+    fn peers_agree_over_a_slow_lossy_link() {
+        let rules = GameRules::default();
+        let [host, client] = loopback_pair(8, 0.3, 5);
+        let mut peers = [
+            Peer::host(Box::new(host), rules.clone(), 11),
+            Peer::client(Box::new(client), rules.clone(), 11, 0),
+        ];
+        for _ in 0..1200 {
+            for (player, peer) in peers.iter_mut().enumerate() {
+                peer.poll(1.0 / 60.0).unwrap();
+                let frame = peer.session.frame();
+                peer.step(scripted(player, frame));
+                peer.flush().unwrap();
+            }
+        }
+        // let the last of the commands get through
+        for _ in 0..200 {
+            for peer in peers.iter_mut() {
+                peer.poll(1.0 / 60.0).unwrap();
+                peer.flush().unwrap();
+            }
+        }
+
+        let [host, client] = &peers;
+        assert!(300 < host.session.confirmed_frame);
+        assert_eq!(host.session.confirmed_frame, client.session.confirmed_frame);
+        assert_eq!(host.session.inputs, client.session.inputs);
+
+        // the same frames played one after another, with nothing guessed
+        let mut sims = [
+            Simulation::new_game(rules.clone(), 11),
+            Simulation::new_game(rules, 11),
+        ];
+        for frame in 0..host.session.confirmed_frame {
+            step_frame(&mut sims, &host.session.inputs, frame);
+        }
+        for peer in &peers {
+            for (sim, expected) in peer.session.confirmed.iter().zip(sims.iter()) {
+                assert_eq!(
+                    sim.common.board.to_string(),
+                    expected.common.board.to_string()
+                );
+                assert_eq!(sim.score, expected.score);
+                assert_eq!(sim.common.drop_count, expected.common.drop_count);
+            }
+        }
+    }
+
+    #[test]
+    fn guesses_are_played_again_when_the_commands_arrive() {
+        let rules = GameRules::default();
+        let mut session = Rollback::new(0, rules, 3);
+        for frame in 0..10 {
+            session.advance(scripted(0, frame));
+        }
+        assert_eq!(session.confirmed_frame, 0);
+        assert_eq!(session.predicted[1].common.drop_count, 0);
+
+        // the other side had hard dropped straight away
+        session.receive(0, &[1 << 3; 10]);
+        session.advance(scripted(0, 10));
+        assert_eq!(session.confirmed_frame, 10);
+        assert_eq!(session.predicted[1].common.drop_count, 1);
+    }
+
+    #[test]
+    fn tcp_carries_packets_whole() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (host, _) = listener.accept().unwrap();
+        let mut client = TcpTransport::new(client).unwrap();
+        let mut host = TcpTransport::new(host).unwrap();
+        let sent = vec![
+            Packet::Hello,
+            Packet::Start {
+                round: 0,
+                seed: 7,
+                rules: Box::default(),
+            },
+        ];
+        for packet in sent.iter() {
+            client.send(packet).unwrap();
+        }
+        let mut received = vec![];
+        for _ in 0..100 {
+            received.extend(host.receive().unwrap());
+            if received.len() == sent.len() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn command_line_picks_how_to_connect() {
+        let args = |line: &str| parse_args(line.split_whitespace().map(str::to_owned));
+        assert_eq!(args(""), Ok(None));
+        assert_eq!(
+            args("--host"),
+            Ok(Some(Connect::Host {
+                port: DEFAULT_PORT,
+                udp: false
+            }))
+        );
+        assert_eq!(
+            args("--join 127.0.0.1 --udp"),
+            Ok(Some(Connect::Join {
+                addr: ([127, 0, 0, 1], DEFAULT_PORT).into(),
+                udp: true
+            }))
+        );
+        assert!(args("--join").is_err());
+        assert!(args("--wat").is_err());
+    }
+}
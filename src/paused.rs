@@ -0,0 +1,199 @@
+use ggez::{
+    graphics::{self, Color, DrawMode, MeshBuilder},
+    Context, GameResult,
+};
+use glam::Vec2;
+
+use crate::input::*;
+use crate::{
+    assets::Assets,
+    drawing::{draw_centered_text, draw_game_play, YELLOW},
+    game_loop::{present, wants_pause, Commands, GameState},
+    helpful_things::time_delta,
+    saved_game::{save_game, SAVED_GAME_PATH},
+    simulation::{effects_rng, Phase, Simulation},
+    the_pillar_descending::ThePillarIsFalling,
+    timer::Timer,
+    title_screen::TitleScreen,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Menu {
+    Main,
+    Settings,
+}
+
+const MAIN_ITEMS: usize = 4;
+const SETTINGS_ITEMS: usize = 3;
+
+/// The game stopped in its tracks with a menu over it.
+/// Nothing moves until it is resumed, after a short countdown.
+pub struct Paused {
+    pub sim: Simulation,
+    menu: Menu,
+    cursor: usize,
+    /// counting down to carrying on, `None` while the menu is up
+    countdown: Option<Timer>,
+}
+
+impl Paused {
+    pub fn new(sim: Simulation) -> Self {
+        Self {
+            sim,
+            menu: Menu::Main,
+            cursor: 0,
+            countdown: None,
+        }
+    }
+
+    fn items(&self, assets: &Assets) -> Vec<String> {
+        match self.menu {
+            Menu::Main => ["resume", "restart", "settings", "quit to title"]
+                .iter()
+                .map(|&s| s.to_owned())
+                .collect(),
+            Menu::Settings => vec![
+                format!("< ghost {} >", self.sim.common.rules.ghost_pillar.name()),
+                format!(
+                    "< sound {} >",
+                    if assets.sound_on.get() { "on" } else { "off" }
+                ),
+                "back".to_owned(),
+            ],
+        }
+    }
+
+    fn update_settings(
+        &mut self,
+        assets: &Assets,
+        input_state: &InputState<PlayerInput, PlayerCommand>,
+    ) {
+        let change = input_state.just_active(PlayerCommand::MoveLeft)
+            || input_state.just_active(PlayerCommand::MoveRight)
+            || input_state.just_active(PlayerCommand::Start);
+        if !change {
+            return;
+        }
+        match self.cursor {
+            0 => {
+                // only changes what is drawn, so the replay is unaffected
+                let rules = &mut self.sim.common.rules;
+                rules.ghost_pillar = rules.ghost_pillar.next();
+            }
+            1 => assets.sound_on.set(!assets.sound_on.get()),
+            _ => {
+                if input_state.just_active(PlayerCommand::Start) {
+                    self.menu = Menu::Main;
+                    self.cursor = 2;
+                }
+            }
+        }
+    }
+}
+
+impl GameState for Paused {
+    fn update(
+        mut self: Box<Self>,
+        ctx: &mut Context,
+        assets: &Assets,
+        input_state: &InputState<PlayerInput, PlayerCommand>,
+        commands: &mut Commands,
+    ) -> Option<Box<dyn GameState>> {
+        if let Some(countdown) = &mut self.countdown {
+            if wants_pause(input_state, commands) {
+                self.countdown = None;
+            } else if countdown.update(time_delta(ctx)) {
+                return Some(present(self.sim, assets, commands));
+            }
+            return Some(self);
+        }
+
+        if input_state.just_active(PlayerCommand::Pause) {
+            self.countdown = Some(Timer::new(3.0));
+            return Some(self);
+        }
+
+        let len = match self.menu {
+            Menu::Main => MAIN_ITEMS,
+            Menu::Settings => SETTINGS_ITEMS,
+        };
+        if input_state.just_active(PlayerCommand::MoveDown) {
+            self.cursor = (self.cursor + 1) % len;
+        } else if input_state.just_active(PlayerCommand::HardDrop) {
+            self.cursor = (self.cursor + len - 1) % len;
+        }
+
+        if self.menu == Menu::Settings {
+            self.update_settings(assets, input_state);
+            return Some(self);
+        }
+        if !input_state.just_active(PlayerCommand::Start) {
+            return Some(self);
+        }
+        match self.cursor {
+            0 => self.countdown = Some(Timer::new(3.0)),
+            1 => {
+                commands.reset_score();
+                let seed = rand::random::<u64>();
+                *assets.effects_rng.borrow_mut() = effects_rng(seed);
+                let rules = self.sim.common.rules.clone();
+                return Some(Box::new(ThePillarIsFalling::new_game(rules, seed)));
+            }
+            2 => {
+                self.menu = Menu::Settings;
+                self.cursor = 0;
+            }
+            _ => {
+                // keep the game so it can be continued from the title screen
+                if let Err(e) = save_game(SAVED_GAME_PATH, &self.sim) {
+                    eprintln!("couldn't save the game: {}", e);
+                }
+                return Some(Box::new(TitleScreen::new()));
+            }
+        }
+        Some(self)
+    }
+
+    fn draw(&self, ctx: &mut Context, assets: &Assets) -> GameResult {
+        let pillar = match self.sim.phase {
+            Phase::Falling | Phase::Landed { .. } => Some((
+                &self.sim.current_pillar,
+                self.sim.pillar_pos.x,
+                self.sim.pillar_pos.y,
+            )),
+            _ => None,
+        };
+        draw_game_play(ctx, assets, &self.sim.common, pillar, None, Vec2::ZERO)?;
+        let rect = graphics::screen_coordinates(ctx);
+        let mesh = MeshBuilder::new()
+            .rectangle(DrawMode::fill(), rect, Color::new(0., 0., 0., 0.7))?
+            .build(ctx)?;
+        graphics::draw(ctx, &mesh, (Vec2::ZERO,))?;
+
+        let y = rect.center().y - 100.0;
+        if let Some(countdown) = self.countdown {
+            let count = countdown.time_left().ceil() as u64;
+            let count_text = graphics::Text::new((count.to_string(), assets.font, 60.0));
+            return draw_centered_text(ctx, &count_text, y, Color::WHITE);
+        }
+
+        let title = graphics::Text::new(("PAUSED", assets.font, 36.0));
+        draw_centered_text(ctx, &title, y, Color::WHITE)?;
+        let mut y = y + 70.0;
+        for (i, item) in self.items(assets).into_iter().enumerate() {
+            let color = if i == self.cursor {
+                YELLOW
+            } else {
+                Color::WHITE
+            };
+            let text = graphics::Text::new((item, assets.font, 25.0));
+            draw_centered_text(ctx, &text, y, color)?;
+            y += 35.0;
+        }
+        Ok(())
+    }
+
+    fn simulation(&self) -> Option<&Simulation> {
+        Some(&self.sim)
+    }
+}
@@ -1,39 +1,62 @@
-use std::ops::Index;
-
 use crate::board::{Block, Palette};
 use rand::Rng;
-#[derive(Clone, Debug)]
-pub struct Pillar {
-    blocks: Vec<Block>,
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Pillar {
+    /// an ordinary pillar of colored blocks
+    Blocks(Vec<Block>),
+    /// a magic pillar of the given length, that clears every block
+    /// of the color it lands on instead of being written to the board
+    Magic(usize),
 }
 
 impl Pillar {
-    pub fn new_random<R: Rng>(rng: &mut R, palette: &Palette, len: usize) -> Self {
-        Self {
-            blocks: (0..len).map(|_| palette.get_random(rng)).collect(),
-        }
+    pub fn new_random<R: Rng + ?Sized>(rng: &mut R, palette: &Palette, len: usize) -> Self {
+        Self::Blocks((0..len).map(|_| palette.get_random(rng)).collect())
+    }
+
+    pub fn new_magic(len: usize) -> Self {
+        Self::Magic(len)
+    }
+
+    pub fn is_magic(&self) -> bool {
+        matches!(self, Self::Magic(_))
     }
 
     pub fn len(&self) -> usize {
-        self.blocks.len()
+        match self {
+            Self::Blocks(blocks) => blocks.len(),
+            Self::Magic(len) => *len,
+        }
     }
 
     pub fn rot_up(&mut self) {
-        self.blocks.rotate_left(1)
+        if let Self::Blocks(blocks) = self {
+            blocks.rotate_left(1)
+        }
     }
 
     pub fn rot_down(&mut self) {
-        self.blocks.rotate_right(1)
+        if let Self::Blocks(blocks) = self {
+            blocks.rotate_right(1)
+        }
     }
 
+    /// The blocks of the pillar from top to bottom, none if it is magic.
     pub fn iter(&self) -> impl Iterator<Item = &Block> {
-        self.blocks.iter()
+        let blocks: &[Block] = match self {
+            Self::Blocks(blocks) => blocks,
+            Self::Magic(_) => &[],
+        };
+        blocks.iter()
     }
-}
 
-impl Index<usize> for Pillar {
-    type Output = Block;
-    fn index(&self, idx: usize) -> &Self::Output {
-        &self.blocks[idx]
+    /// The `idx`th block from the top, none if it is magic.
+    pub fn get(&self, idx: usize) -> Option<Block> {
+        match self {
+            Self::Blocks(blocks) => blocks.get(idx).copied(),
+            Self::Magic(_) => None,
+        }
     }
 }
@@ -0,0 +1,216 @@
+use std::{collections::BTreeMap, error::Error, path::Path};
+
+use ron::de::from_reader;
+use ron::ser::to_writer;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{fall_down, Block, Board, ParseBoardError},
+    pillar::Pillar,
+    the_rules::GameRules,
+};
+
+/// Which puzzles have been solved, kept next to the high scores.
+pub const PUZZLE_PROGRESS_PATH: &str = "puzzle_progress";
+
+/// What has to be done before the pillars run out.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Goal {
+    /// leave the board empty
+    ClearAll,
+    /// leave no blocks of this color
+    ClearColor(Block),
+    /// clear this many times in a row with one pillar
+    Chain(u64),
+}
+
+impl Goal {
+    pub fn describe(self) -> String {
+        match self {
+            Self::ClearAll => "clear all blocks".to_owned(),
+            Self::ClearColor(block) => format!("clear every {:?} block", block).to_lowercase(),
+            Self::Chain(length) => format!("make a chain of {}", length),
+        }
+    }
+
+    /// Checked once the board has settled after a pillar,
+    /// `chain` being how many clears in a row it set off.
+    pub fn is_met(self, board: &Board, chain: u64) -> bool {
+        let mut blocks = (0..board.x_len())
+            .flat_map(|x| (0..board.y_len()).map(move |y| [x, y]))
+            .filter_map(|idx| board[idx]);
+        match self {
+            Self::ClearAll => blocks.next().is_none(),
+            Self::ClearColor(color) => blocks.all(|block| block != color),
+            Self::Chain(length) => length <= chain,
+        }
+    }
+}
+
+/// A board to start from and the only pillars there are to deal with it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Puzzle {
+    pub goal: Goal,
+    /// the bottom rows of the board as text, see `Board`'s `Display`
+    pub board: String,
+    /// dealt in order, the first one straight away
+    pub pillars: Vec<Pillar>,
+}
+
+impl Puzzle {
+    /// The starting board, with room above it like any other game.
+    /// Blocks placed in mid air fall to rest before the first pillar.
+    pub fn board(&self, rules: &GameRules) -> Result<Board, ParseBoardError> {
+        let sz = [rules.board_sz[0], rules.board_sz[1] + rules.pillar_sz + 1];
+        let mut board = Board::from_bottom_rows(sz, &self.board)?;
+        fall_down(&mut board);
+        Ok(board)
+    }
+}
+
+/// The fewest pillars each puzzle has been solved with, by name.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PuzzleProgress {
+    solved: BTreeMap<String, u64>,
+}
+
+impl PuzzleProgress {
+    pub fn best(&self, name: &str) -> Option<u64> {
+        self.solved.get(name).copied()
+    }
+
+    pub fn solved_count(&self) -> usize {
+        self.solved.len()
+    }
+
+    /// Returns true if it is the first solution or uses fewer pillars.
+    pub fn record(&mut self, name: &str, pillars: u64) -> bool {
+        match self.solved.get(name) {
+            Some(&best) if best <= pillars => false,
+            _ => {
+                self.solved.insert(name.to_owned(), pillars);
+                true
+            }
+        }
+    }
+}
+
+pub fn load_progress<P: AsRef<Path>>(progress_path: P) -> Result<PuzzleProgress, Box<dyn Error>> {
+    let progress_file = std::fs::File::open(progress_path)?;
+    Ok(from_reader(progress_file)?)
+}
+
+pub fn save_progress<P: AsRef<Path>>(
+    progress_path: P,
+    progress: &PuzzleProgress,
+) -> Result<(), Box<dyn Error>> {
+    let progress_file = std::fs::File::create(progress_path)?;
+    to_writer(progress_file, progress)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Goal, Puzzle, PuzzleProgress};
+    use crate::board::Block::*;
+    use crate::input::{default_input_cfg, InputState, PlayerCommand};
+    use crate::pillar::Pillar;
+    use crate::simulation::{Ending, Phase, Simulation};
+    use crate::the_rules::{load_rules, GameMode, GameRules};
+
+    fn puzzle_rules(goal: Goal, pillars: Vec<Pillar>) -> GameRules {
+        let puzzle = Puzzle {
+            goal,
+            board: "BB...\nRR.GG".to_owned(),
+            pillars,
+        };
+        GameRules {
+            mode: GameMode::Puzzle,
+            puzzle: Some(puzzle),
+            board_sz: [5, 8],
+            ..Default::default()
+        }
+    }
+
+    /// Hard drop every pillar where it spawns until the puzzle is over.
+    fn play(rules: GameRules) -> Simulation {
+        let mut sim = Simulation::new_game(rules, 0);
+        let mut input_state = InputState::new(default_input_cfg());
+        for frame in 0..1000 {
+            if frame % 2 == 0 {
+                input_state.activate_command(PlayerCommand::HardDrop);
+            } else {
+                input_state.deactivate_command(PlayerCommand::HardDrop);
+            }
+            sim.update(1.0 / 60.0, &input_state);
+            input_state.save_current();
+            if sim.is_game_over() {
+                break;
+            }
+        }
+        sim
+    }
+
+    #[test]
+    fn a_chain_solves_the_puzzle() {
+        let rules = GameRules {
+            pillar_spawn_pt: crate::simulation::pilpos(2, 3.0),
+            ..puzzle_rules(Goal::Chain(3), vec![Pillar::Blocks(vec![Blue, Green, Red])])
+        };
+        let sim = play(rules);
+        assert!(matches!(
+            sim.phase,
+            Phase::GameOver {
+                ending: Ending::Finished
+            }
+        ));
+        assert_eq!(sim.chain, 3);
+    }
+
+    #[test]
+    fn running_out_of_pillars_fails_the_puzzle() {
+        let rules = GameRules {
+            pillar_spawn_pt: crate::simulation::pilpos(2, 3.0),
+            ..puzzle_rules(
+                Goal::Chain(4),
+                vec![
+                    Pillar::Blocks(vec![Blue, Green, Red]),
+                    Pillar::Blocks(vec![Purple, Purple, Yellow]),
+                ],
+            )
+        };
+        let sim = play(rules);
+        assert!(matches!(
+            sim.phase,
+            Phase::GameOver {
+                ending: Ending::OutOfPillars
+            }
+        ));
+        assert_eq!(sim.common.drop_count, 2);
+    }
+
+    #[test]
+    fn only_better_solutions_are_recorded() {
+        let mut progress = PuzzleProgress::default();
+        assert!(progress.record("first", 3));
+        assert!(!progress.record("first", 4));
+        assert!(progress.record("first", 2));
+        assert_eq!(progress.best("first"), Some(2));
+        assert_eq!(progress.solved_count(), 1);
+    }
+
+    #[test]
+    fn shipped_puzzles_are_valid() {
+        let puzzles_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/puzzles");
+        let mut count = 0;
+        for entry in std::fs::read_dir(puzzles_dir).unwrap() {
+            let file = std::fs::File::open(entry.unwrap().path()).unwrap();
+            let rules = load_rules(file).unwrap();
+            assert!(rules.puzzle().is_some());
+            count += 1;
+        }
+        assert!(3 <= count);
+    }
+}
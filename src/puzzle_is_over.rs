@@ -0,0 +1,151 @@
+use ggez::{
+    graphics::{self, Color, DrawMode, MeshBuilder},
+    Context, GameResult,
+};
+use glam::Vec2;
+
+use crate::input::*;
+use crate::{
+    assets::Assets,
+    drawing::{draw_centered_text, draw_game_play, lerp_color, GREEN, RED},
+    game_loop::{Commands, GameState},
+    helpful_things::time_delta,
+    puzzle::{load_progress, save_progress, PUZZLE_PROGRESS_PATH},
+    simulation::{effects_rng, CommonState, Ending},
+    the_pillar_descending::ThePillarIsFalling,
+    the_rules::GameRules,
+    title_screen::TitleScreen,
+};
+
+/// A puzzle solved or failed, with the way on to the next one or another go.
+pub struct PuzzleIsOver {
+    pub common: CommonState,
+    pub ending: Ending,
+    pub fade: f32,
+    /// the fewest pillars it has been solved with, this time included
+    pub best: Option<u64>,
+}
+
+impl PuzzleIsOver {
+    pub fn new(common: CommonState, ending: Ending) -> Self {
+        let name = common.rules.name.as_str();
+        let mut progress = load_progress(PUZZLE_PROGRESS_PATH).unwrap_or_default();
+        if ending == Ending::Finished && progress.record(name, common.drop_count) {
+            if let Err(e) = save_progress(PUZZLE_PROGRESS_PATH, &progress) {
+                eprintln!("couldn't save the puzzle progress: {}", e);
+            }
+        }
+        let best = progress.best(name);
+        Self {
+            common,
+            ending,
+            fade: 0.0,
+            best,
+        }
+    }
+
+    fn solved(&self) -> bool {
+        self.ending == Ending::Finished
+    }
+
+    /// The puzzle after this one, if there is one.
+    fn next_puzzle<'a>(&self, assets: &'a Assets) -> Option<&'a GameRules> {
+        let name = &self.common.rules.name;
+        let index = assets.puzzles.iter().position(|p| &p.name == name)?;
+        assets.puzzles.get(index + 1)
+    }
+
+    fn headline(&self) -> &'static str {
+        match self.ending {
+            Ending::Finished => "SOLVED",
+            Ending::OutOfPillars => "OUT OF PILLARS",
+            Ending::ToppedOut | Ending::TimeUp => "TOPPED OUT",
+        }
+    }
+}
+
+fn start_puzzle(rules: GameRules, assets: &Assets, commands: &mut Commands) -> Box<dyn GameState> {
+    commands.reset_score();
+    let seed = rand::random::<u64>();
+    *assets.effects_rng.borrow_mut() = effects_rng(seed);
+    Box::new(ThePillarIsFalling::new_game(rules, seed))
+}
+
+impl GameState for PuzzleIsOver {
+    fn update(
+        mut self: Box<Self>,
+        ctx: &mut Context,
+        assets: &Assets,
+        input_state: &InputState<PlayerInput, PlayerCommand>,
+        commands: &mut Commands,
+    ) -> Option<Box<dyn GameState>> {
+        self.fade = (self.fade + 0.5 * time_delta(ctx)).min(0.9);
+        if input_state.just_active(PlayerCommand::Continue) {
+            return Some(Box::new(TitleScreen::new()));
+        }
+        if input_state.just_active(PlayerCommand::Start) {
+            if !self.solved() {
+                let rules = self.common.rules.clone();
+                return Some(start_puzzle(rules, assets, commands));
+            }
+            return match self.next_puzzle(assets) {
+                Some(rules) => Some(start_puzzle(rules.clone(), assets, commands)),
+                None => Some(Box::new(TitleScreen::new())),
+            };
+        }
+        Some(self)
+    }
+
+    fn draw(&self, ctx: &mut Context, assets: &Assets) -> GameResult {
+        draw_game_play(ctx, assets, &self.common, None, None, Vec2::ZERO)?;
+        let rect = graphics::screen_coordinates(ctx);
+        let mesh = MeshBuilder::new()
+            .rectangle(DrawMode::fill(), rect, Color::new(0., 0., 0., self.fade))?
+            .build(ctx)?;
+        graphics::draw(ctx, &mesh, (Vec2::ZERO,))?;
+
+        let y = rect.center().y - 200.0;
+        let flash = if self.solved() { GREEN } else { RED };
+        let headline = graphics::Text::new((self.headline(), assets.font, 36.0));
+        let t = ggez::timer::time_since_start(ctx).as_secs_f32();
+        draw_centered_text(
+            ctx,
+            &headline,
+            y,
+            lerp_color(Color::WHITE, flash, t.sin().abs()),
+        )?;
+
+        let goal = self.common.rules.puzzle().map(|p| p.goal.describe());
+        let lines = [
+            self.common.rules.name.clone(),
+            goal.unwrap_or_default(),
+            match self.best {
+                Some(best) if self.solved() => format!(
+                    "solved with {} pillars, best {}",
+                    self.common.drop_count, best
+                ),
+                Some(best) => format!("solved before with {} pillars", best),
+                None => "not solved yet".to_owned(),
+            },
+        ];
+        let mut y = y + 60.0;
+        for line in lines {
+            let text = graphics::Text::new((line, assets.font, 20.0));
+            draw_centered_text(ctx, &text, y, Color::WHITE)?;
+            y += 30.0;
+        }
+
+        let next = if !self.solved() {
+            "start to try again"
+        } else if self.next_puzzle(assets).is_some() {
+            "start for the next puzzle"
+        } else {
+            "start to finish"
+        };
+        let y = y + 30.0;
+        let next_text = graphics::Text::new((next, assets.font, 25.0));
+        draw_centered_text(ctx, &next_text, y, Color::WHITE)?;
+        let title_text = graphics::Text::new(("enter for the title screen", assets.font, 18.0));
+        draw_centered_text(ctx, &title_text, y + 35.0, Color::WHITE)
+    }
+}
@@ -0,0 +1,150 @@
+use rand::{seq::SliceRandom, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{Block, Palette},
+    pillar::Pillar,
+    the_rules::RandomizerKind,
+};
+
+/// Deals the colors of the ordinary pillars. Magic pillars are dealt
+/// by the simulation on their own schedule.
+pub trait Randomizer {
+    fn next_pillar(&mut self, rng: &mut dyn RngCore, palette: &Palette, len: usize) -> Pillar;
+}
+
+impl RandomizerKind {
+    pub fn build(self) -> AnyRandomizer {
+        match self {
+            Self::PureRandom => AnyRandomizer::PureRandom(PureRandom),
+            Self::ColorBag => AnyRandomizer::ColorBag(ColorBag::default()),
+            Self::NoRepeats => AnyRandomizer::NoRepeats(NoRepeats::default()),
+        }
+    }
+}
+
+/// One of the randomizers below, so a game in progress can be saved with it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum AnyRandomizer {
+    PureRandom(PureRandom),
+    ColorBag(ColorBag),
+    NoRepeats(NoRepeats),
+}
+
+impl Randomizer for AnyRandomizer {
+    fn next_pillar(&mut self, rng: &mut dyn RngCore, palette: &Palette, len: usize) -> Pillar {
+        match self {
+            Self::PureRandom(r) => r.next_pillar(rng, palette, len),
+            Self::ColorBag(r) => r.next_pillar(rng, palette, len),
+            Self::NoRepeats(r) => r.next_pillar(rng, palette, len),
+        }
+    }
+}
+
+/// Every block is picked on its own from the palette.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PureRandom;
+
+impl Randomizer for PureRandom {
+    fn next_pillar(&mut self, rng: &mut dyn RngCore, palette: &Palette, len: usize) -> Pillar {
+        Pillar::new_random(rng, palette, len)
+    }
+}
+
+/// Blocks are drawn from a shuffled bag holding the same number of each color,
+/// so no color can go missing for long.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ColorBag {
+    bag: Vec<Block>,
+    /// size of the palette the bag was filled from
+    filled_from: usize,
+}
+
+impl ColorBag {
+    /// copies of each color in a full bag
+    const COPIES: usize = 3;
+
+    fn draw(&mut self, rng: &mut dyn RngCore, palette: &Palette) -> Block {
+        // start a new bag when the palette grows, so new colors turn up straight away
+        if self.bag.is_empty() || self.filled_from != palette.blocks().len() {
+            self.bag = palette
+                .blocks()
+                .iter()
+                .flat_map(|&block| std::iter::repeat_n(block, Self::COPIES))
+                .collect();
+            self.bag.shuffle(rng);
+            self.filled_from = palette.blocks().len();
+        }
+        self.bag.pop().expect("palette is empty")
+    }
+}
+
+impl Randomizer for ColorBag {
+    fn next_pillar(&mut self, rng: &mut dyn RngCore, palette: &Palette, len: usize) -> Pillar {
+        Pillar::Blocks((0..len).map(|_| self.draw(rng, palette)).collect())
+    }
+}
+
+/// Pure random, but never the same pillar twice in a row.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct NoRepeats {
+    last: Option<Pillar>,
+}
+
+impl NoRepeats {
+    /// gives up eventually, one color palettes can only make one pillar
+    const MAX_TRIES: usize = 16;
+}
+
+impl Randomizer for NoRepeats {
+    fn next_pillar(&mut self, rng: &mut dyn RngCore, palette: &Palette, len: usize) -> Pillar {
+        let mut pillar = Pillar::new_random(rng, palette, len);
+        for _ in 1..Self::MAX_TRIES {
+            if self.last.as_ref() != Some(&pillar) {
+                break;
+            }
+            pillar = Pillar::new_random(rng, palette, len);
+        }
+        self.last = Some(pillar.clone());
+        pillar
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::{prelude::StdRng, SeedableRng};
+
+    use super::{ColorBag, NoRepeats, Randomizer};
+    use crate::board::Palette;
+
+    #[test]
+    fn bag_deals_every_color_equally() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let palette = Palette::new(3);
+        let mut bag = ColorBag::default();
+        let mut counts = HashMap::new();
+        // three pillars of three empty a bag of three copies of three colors
+        for _ in 0..3 {
+            for &block in bag.next_pillar(&mut rng, &palette, 3).iter() {
+                *counts.entry(block).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(counts.len(), 3);
+        assert!(counts.values().all(|&n| n == 3));
+    }
+
+    #[test]
+    fn no_pillar_is_dealt_twice_in_a_row() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let palette = Palette::new(2);
+        let mut randomizer = NoRepeats::default();
+        let mut last = randomizer.next_pillar(&mut rng, &palette, 2);
+        for _ in 0..200 {
+            let pillar = randomizer.next_pillar(&mut rng, &palette, 2);
+            assert_ne!(pillar, last);
+            last = pillar;
+        }
+    }
+}
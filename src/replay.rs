@@ -0,0 +1,225 @@
+use std::{collections::HashMap, error::Error, hash::Hash, path::Path};
+
+use ron::de::from_reader;
+use ron::ser::{to_writer_pretty, PrettyConfig};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::{InputState, PlayerCommand, PlayerInput},
+    the_rules::GameRules,
+};
+
+/// Bumped whenever the replay format or the rules of the simulation change,
+/// so old replays are turned away instead of playing out differently.
+pub const REPLAY_VERSION: u32 = 2;
+
+/// The last game played, kept next to the high scores.
+pub const LAST_REPLAY_PATH: &str = "last_replay";
+
+/// A command being pressed or released.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CommandEvent {
+    /// the frame it happened on, its time is the sum of the earlier time steps
+    pub frame: usize,
+    pub command: PlayerCommand,
+    pub active: bool,
+}
+
+/// Everything needed to play a game again exactly as it went.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Replay {
+    pub version: u32,
+    pub seed: u64,
+    pub rules: GameRules,
+    /// commands that were already held when the game began
+    pub held_at_start: Vec<PlayerCommand>,
+    /// seconds the game was advanced by on each frame
+    pub time_steps: Vec<f32>,
+    pub events: Vec<CommandEvent>,
+}
+
+impl Replay {
+    pub fn new(seed: u64, rules: GameRules) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            seed,
+            rules,
+            held_at_start: vec![],
+            time_steps: vec![],
+            events: vec![],
+        }
+    }
+
+    /// Note down a frame's time step and the commands that changed since the last one.
+    pub fn record<I>(&mut self, time_delta: f32, input_state: &InputState<I, PlayerCommand>)
+    where
+        I: Copy + Eq + Hash,
+    {
+        let frame = self.time_steps.len();
+        if frame == 0 {
+            self.held_at_start = PlayerCommand::GAMEPLAY
+                .iter()
+                .copied()
+                .filter(|&c| input_state.was_active(c))
+                .collect();
+        }
+        for command in PlayerCommand::GAMEPLAY {
+            if input_state.just_active(command) || input_state.just_inactive(command) {
+                self.events.push(CommandEvent {
+                    frame,
+                    command,
+                    active: input_state.active(command),
+                });
+            }
+        }
+        self.time_steps.push(time_delta);
+    }
+}
+
+pub fn load_replay<P: AsRef<Path>>(replay_path: P) -> Result<Replay, Box<dyn Error>> {
+    let replay_file = std::fs::File::open(replay_path)?;
+    let replay: Replay = from_reader(replay_file)?;
+    if replay.version != REPLAY_VERSION {
+        return Err(format!(
+            "replay is version {}, this game plays version {}",
+            replay.version, REPLAY_VERSION
+        )
+        .into());
+    }
+    // the game is dealt again from these, which expects them to be playable
+    replay.rules.validate()?;
+    Ok(replay)
+}
+
+pub fn save_replay<P: AsRef<Path>>(replay_path: P, replay: &Replay) -> Result<(), Box<dyn Error>> {
+    let replay_file = std::fs::File::create(replay_path)?;
+    to_writer_pretty(replay_file, replay, PrettyConfig::default())?;
+    Ok(())
+}
+
+/// Feeds a recorded game back in, a frame at a time, through its own `InputState`.
+pub struct Playback {
+    replay: Replay,
+    frame: usize,
+    next_event: usize,
+    pub input_state: InputState<PlayerInput, PlayerCommand>,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Self {
+        let mut input_state = InputState::new(HashMap::new());
+        for &command in replay.held_at_start.iter() {
+            input_state.activate_command(command);
+        }
+        Self {
+            replay,
+            frame: 0,
+            next_event: 0,
+            input_state,
+        }
+    }
+
+    /// Press and release the commands recorded for the next frame
+    /// and return its time step, or `None` once the replay has run out.
+    pub fn advance(&mut self) -> Option<f32> {
+        let time_step = *self.replay.time_steps.get(self.frame)?;
+        self.input_state.save_current();
+        while let Some(event) = self.replay.events.get(self.next_event) {
+            if event.frame != self.frame {
+                break;
+            }
+            if event.active {
+                self.input_state.activate_command(event.command);
+            } else {
+                self.input_state.deactivate_command(event.command);
+            }
+            self.next_event += 1;
+        }
+        self.frame += 1;
+        Some(time_step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_replay, save_replay, Playback, Replay};
+    use crate::board::Block;
+    use crate::input::{default_input_cfg, InputState, PlayerCommand};
+    use crate::pillar::Pillar;
+    use crate::puzzle::{Goal, Puzzle};
+    use crate::simulation::Simulation;
+    use crate::the_rules::{GameMode, GameRules, InvalidRules};
+
+    #[test]
+    fn a_recorded_game_plays_back_exactly() {
+        let mut sim = Simulation::new_game(GameRules::default(), 11);
+        sim.record_replay();
+        let mut input_state = InputState::new(default_input_cfg());
+        input_state.activate_command(PlayerCommand::MoveDown);
+        input_state.save_current();
+        let script = [
+            PlayerCommand::MoveLeft,
+            PlayerCommand::RotUp,
+            PlayerCommand::HardDrop,
+            PlayerCommand::MoveRight,
+            PlayerCommand::MoveRight,
+            PlayerCommand::HardDrop,
+        ];
+        for frame in 0..3000 {
+            let command = script[(frame / 7) % script.len()];
+            if frame % 7 == 0 {
+                input_state.activate_command(command);
+            } else if frame % 7 == 3 {
+                input_state.deactivate_command(command);
+            }
+            let time_step = if frame % 2 == 0 {
+                1.0 / 60.0
+            } else {
+                1.0 / 55.0
+            };
+            sim.update(time_step, &input_state);
+            input_state.save_current();
+            if sim.is_game_over() {
+                break;
+            }
+        }
+        let recorded = sim.replay.as_ref().unwrap();
+        let text = ron::to_string(recorded).unwrap();
+        let replay: Replay = ron::from_str(&text).unwrap();
+        assert_eq!(&replay, recorded);
+
+        let mut copy = Simulation::new_game(replay.rules.clone(), replay.seed);
+        let mut playback = Playback::new(replay);
+        while let Some(time_step) = playback.advance() {
+            copy.update(time_step, &playback.input_state);
+        }
+        assert_eq!(copy.common.board, sim.common.board);
+        assert_eq!(copy.score, sim.score);
+        assert_eq!(copy.current_pillar, sim.current_pillar);
+        assert!(0 < copy.common.drop_count);
+        // only games that asked to be recorded keep their inputs
+        assert!(copy.replay.is_none());
+    }
+
+    #[test]
+    fn a_replay_with_a_broken_puzzle_is_turned_away() {
+        let rules = GameRules {
+            mode: GameMode::Puzzle,
+            puzzle: Some(Puzzle {
+                goal: Goal::ClearAll,
+                board: "RR?".to_owned(),
+                pillars: vec![Pillar::Blocks(vec![Block::Red; 3])],
+            }),
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("test_replay_{}", std::process::id()));
+        save_replay(&path, &Replay::new(5, rules)).unwrap();
+        let loaded = load_replay(&path);
+        std::fs::remove_file(&path).unwrap();
+        let err = loaded.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<InvalidRules>(),
+            Some(InvalidRules::PuzzleBoard(_))
+        ));
+    }
+}
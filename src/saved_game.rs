@@ -0,0 +1,100 @@
+use std::{error::Error, path::Path};
+
+use ron::de::from_reader;
+use ron::ser::to_writer;
+use serde::{Deserialize, Serialize};
+
+use crate::simulation::Simulation;
+
+/// Bumped whenever the simulation's fields change, so old saves are turned away.
+pub const SAVE_VERSION: u32 = 7;
+
+/// An unfinished game, kept next to the high scores.
+pub const SAVED_GAME_PATH: &str = "saved_game";
+
+#[derive(Deserialize, Serialize)]
+struct SavedGame {
+    version: u32,
+    sim: Simulation,
+}
+
+pub fn has_saved_game<P: AsRef<Path>>(saved_game_path: P) -> bool {
+    saved_game_path.as_ref().exists()
+}
+
+pub fn load_game<P: AsRef<Path>>(saved_game_path: P) -> Result<Simulation, Box<dyn Error>> {
+    let saved_game_file = std::fs::File::open(saved_game_path)?;
+    let saved_game: SavedGame = from_reader(saved_game_file)?;
+    if saved_game.version != SAVE_VERSION {
+        return Err(format!(
+            "saved game is version {}, this game loads version {}",
+            saved_game.version, SAVE_VERSION
+        )
+        .into());
+    }
+    // retrying starts a new game from these, which expects them to be playable
+    saved_game.sim.common.rules.validate()?;
+    Ok(saved_game.sim)
+}
+
+pub fn save_game<P: AsRef<Path>>(
+    saved_game_path: P,
+    sim: &Simulation,
+) -> Result<(), Box<dyn Error>> {
+    let saved_game_file = std::fs::File::create(saved_game_path)?;
+    let saved_game = SavedGame {
+        version: SAVE_VERSION,
+        sim: sim.clone(),
+    };
+    to_writer(saved_game_file, &saved_game)?;
+    Ok(())
+}
+
+/// Forget the saved game once it has been picked up again.
+pub fn remove_saved_game<P: AsRef<Path>>(saved_game_path: P) -> Result<(), Box<dyn Error>> {
+    std::fs::remove_file(saved_game_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_game, remove_saved_game, save_game};
+    use crate::input::{default_input_cfg, InputState, PlayerCommand};
+    use crate::simulation::Simulation;
+    use crate::the_rules::{GameRules, RandomizerKind};
+
+    #[test]
+    fn a_saved_game_carries_on_where_it_stopped() {
+        let rules = GameRules {
+            randomizer: RandomizerKind::ColorBag,
+            ..Default::default()
+        };
+        let mut sim = Simulation::new_game(rules, 3);
+        sim.record_replay();
+        let mut input_state = InputState::new(default_input_cfg());
+        for frame in 0..400 {
+            if frame % 20 == 0 {
+                input_state.activate_command(PlayerCommand::HardDrop);
+            } else {
+                input_state.deactivate_command(PlayerCommand::HardDrop);
+            }
+            sim.update(1.0 / 60.0, &input_state);
+            input_state.save_current();
+        }
+        let path = std::env::temp_dir().join(format!("test_saved_game_{}", std::process::id()));
+        save_game(&path, &sim).unwrap();
+        let mut resumed = load_game(&path).unwrap();
+        remove_saved_game(&path).unwrap();
+        assert!(!path.exists());
+
+        for _ in 0..600 {
+            sim.update(1.0 / 60.0, &input_state);
+            resumed.update(1.0 / 60.0, &input_state);
+        }
+        assert_eq!(resumed.common.board, sim.common.board);
+        assert_eq!(resumed.score, sim.score);
+        assert_eq!(resumed.common.next_pillars, sim.common.next_pillars);
+        assert!(sim.replay.is_some());
+        assert_eq!(resumed.replay, sim.replay);
+    }
+}
@@ -2,28 +2,107 @@ use ron::de::from_reader;
 use ron::ser::to_writer;
 use std::{error::Error, path::Path};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-pub fn calculate_points(matched_blocks: u64, level: u64) -> u64 {
-    let multiplier = (matched_blocks as f64).log(1.4) + (level as f64).log(1.3);
-    let points = 5.0 * matched_blocks as f64 * multiplier;
-    points.ceil() as u64
+use crate::the_rules::{GameMode, RiseEvery};
+
+/// The points scored by one step of a chain, split up for the HUD.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ScoreBreakdown {
+    /// position of this step in the chain, starting at 1
+    pub chain: u64,
+    /// separate groups cleared at once
+    pub groups: u64,
+    pub base: u64,
+    pub chain_bonus: u64,
+    pub group_bonus: u64,
+    pub level_bonus: u64,
+}
+
+impl ScoreBreakdown {
+    pub fn total(&self) -> u64 {
+        self.base + self.chain_bonus + self.group_bonus + self.level_bonus
+    }
+}
+
+/// Score one step of a chain. Every step deeper into the chain doubles the base points,
+/// and every extra group cleared at the same time adds half again.
+pub fn calculate_points(
+    matched_blocks: u64,
+    groups: u64,
+    chain: u64,
+    level: u64,
+) -> ScoreBreakdown {
+    let m = matched_blocks as f64;
+    let base = (5.0 * m * m.log(1.4).max(1.0)).ceil() as u64;
+    let level_bonus = (5.0 * m * (level as f64).log(1.3)).ceil() as u64;
+    let chain_multiplier = 1u64 << chain.saturating_sub(1).min(16);
+    ScoreBreakdown {
+        chain,
+        groups,
+        base,
+        chain_bonus: base * (chain_multiplier - 1),
+        group_bonus: base * groups.saturating_sub(1) / 2,
+        level_bonus,
+    }
+}
+
+/// Points for the blocks cleared by a magic pillar, which don't get
+/// the bonuses for chains or big matches.
+pub fn calculate_magic_points(cleared_blocks: u64, level: u64) -> ScoreBreakdown {
+    ScoreBreakdown {
+        chain: 1,
+        groups: 1,
+        base: 10 * cleared_blocks,
+        level_bonus: 10 * cleared_blocks * level.saturating_sub(1),
+        ..Default::default()
+    }
+}
+
+/// Bonus for hard dropping a pillar `cells` rows.
+pub fn calculate_drop_bonus(cells: u64, bonus_per_cell: u64) -> u64 {
+    cells * bonus_per_cell
 }
 
 pub const HIGH_SCORE_PATH: &str = "high_scores";
 
-pub fn load_high_scores_table<P: AsRef<Path>>(
+/// Where the high scores for a mode are kept. Marathon uses the original table.
+pub fn high_score_path(mode: GameMode) -> String {
+    match mode {
+        GameMode::Marathon => HIGH_SCORE_PATH.to_owned(),
+        GameMode::TimeAttack { seconds } => format!("{}_time_attack_{}", HIGH_SCORE_PATH, seconds),
+        GameMode::Sprint { blocks } => format!("{}_sprint_{}", HIGH_SCORE_PATH, blocks),
+        GameMode::RisingFloor {
+            every: RiseEvery::Seconds(seconds),
+        } => format!("{}_rising_{}s", HIGH_SCORE_PATH, seconds),
+        GameMode::RisingFloor {
+            every: RiseEvery::Drops(drops),
+        } => format!("{}_rising_{}_drops", HIGH_SCORE_PATH, drops),
+        // puzzles aren't scored, their progress is kept instead
+        GameMode::Puzzle => format!("{}_puzzle", HIGH_SCORE_PATH),
+    }
+}
+
+pub fn load_high_scores_table<H, P>(
     high_scores_path: P,
-) -> Result<HighScoresTable<HighScore>, Box<dyn Error>> {
+) -> Result<HighScoresTable<H>, Box<dyn Error>>
+where
+    H: Ord + Clone + DeserializeOwned,
+    P: AsRef<Path>,
+{
     let high_scores_file = std::fs::File::open(high_scores_path)?;
     let high_scores_table = from_reader(high_scores_file)?;
     Ok(high_scores_table)
 }
 
-pub fn save_high_score_table<P: AsRef<Path>>(
+pub fn save_high_score_table<H, P>(
     high_scores_path: P,
-    high_score_table: &HighScoresTable<HighScore>,
-) -> Result<(), Box<dyn Error>> {
+    high_score_table: &HighScoresTable<H>,
+) -> Result<(), Box<dyn Error>>
+where
+    H: Ord + Clone + Serialize,
+    P: AsRef<Path>,
+{
     let high_score_file = std::fs::File::create(high_scores_path)?;
     to_writer(high_score_file, high_score_table)?;
     Ok(())
@@ -34,6 +113,9 @@ pub struct HighScore {
     pub score: u64,
     pub level: u64,
     pub handle: String,
+    /// hints were shown during the game
+    #[serde(default)]
+    pub hinted: bool,
 }
 
 impl Ord for HighScore {
@@ -44,7 +126,39 @@ impl Ord for HighScore {
 
 impl PartialOrd for HighScore {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.score.cmp(&other.score))
+        Some(self.cmp(other))
+    }
+}
+
+/// A finished sprint, where the quickest comes first.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SprintTime {
+    pub millis: u64,
+    pub level: u64,
+    pub handle: String,
+    /// hints were shown during the sprint
+    #[serde(default)]
+    pub hinted: bool,
+}
+
+impl Ord for SprintTime {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other.millis.cmp(&self.millis)
+    }
+}
+
+impl PartialOrd for SprintTime {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Follows entries in the high score lists that were played with hints.
+pub fn hint_mark(hinted: bool) -> &'static str {
+    if hinted {
+        "*"
+    } else {
+        " "
     }
 }
 
@@ -103,7 +217,10 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{load_high_scores_table, save_high_score_table, HighScore, HighScoresTable};
+    use super::{
+        calculate_points, load_high_scores_table, save_high_score_table, HighScore,
+        HighScoresTable, SprintTime,
+    };
 
     #[test]
     fn loading_and_saves() {
@@ -111,12 +228,51 @@ mod tests {
             score: 11,
             level: 10,
             handle: "agr".to_owned(),
+            hinted: false,
         };
         let mut t = HighScoresTable::new();
         t.insert(h);
         assert_eq!(t.best().unwrap().score, 11);
         let _ = save_high_score_table("h_file", &t);
-        let u = load_high_scores_table("h_file").unwrap();
+        let u: HighScoresTable<HighScore> = load_high_scores_table("h_file").unwrap();
         assert_eq!(u.best().unwrap().score, 11);
     }
+
+    #[test]
+    fn scores_from_before_hints_load_unhinted() {
+        let h: HighScore = ron::from_str("(score: 5, level: 2, handle: \"agr\")").unwrap();
+        assert!(!h.hinted);
+    }
+
+    #[test]
+    fn faster_sprints_rank_first() {
+        let time = |millis| SprintTime {
+            millis,
+            level: 1,
+            handle: "agr".to_owned(),
+            hinted: false,
+        };
+        let mut t = HighScoresTable::new();
+        t.insert(time(90_000));
+        t.insert(time(60_000));
+        assert_eq!(t.best().unwrap().millis, 60_000);
+        assert_eq!(t.find_position(&time(75_000)), 1);
+    }
+
+    #[test]
+    fn chains_and_groups_earn_bonuses() {
+        let single = calculate_points(3, 1, 1, 1);
+        assert_eq!(single.chain_bonus, 0);
+        assert_eq!(single.group_bonus, 0);
+        assert_eq!(single.level_bonus, 0);
+        assert_eq!(single.total(), single.base);
+
+        let chained = calculate_points(3, 1, 3, 1);
+        assert_eq!(chained.base, single.base);
+        assert_eq!(chained.chain_bonus, 3 * single.base);
+
+        let grouped = calculate_points(6, 2, 1, 5);
+        assert_eq!(grouped.group_bonus, grouped.base / 2);
+        assert!(0 < grouped.level_bonus);
+    }
 }
@@ -0,0 +1,1127 @@
+use std::{collections::VecDeque, hash::Hash};
+
+use glam::{vec2, Vec2};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{
+        count_groups, fall_down, find_matches, garbage_next_to, is_game_over, push_up, resting_pos,
+        write_pillar, Block, Board, BoardCell, Palette,
+    },
+    input::{InputState, PlayerCommand},
+    pillar::Pillar,
+    randomizer::{AnyRandomizer, Randomizer},
+    replay::Replay,
+    scoring::{self, ScoreBreakdown},
+    the_rules::{GameMode, GameRules, GarbageRows, GhostPillar, RiseEvery},
+    timer::Timer,
+};
+
+/// The generator behind everything dealt from a seed. Named explicitly rather
+/// than `StdRng`, whose algorithm may change, so a seed always deals the same game.
+pub type GameRng = ChaCha8Rng;
+
+const GAMEPLAY_STREAM: u64 = 0;
+const EFFECTS_STREAM: u64 = 1;
+const GARBAGE_STREAM: u64 = 2;
+const BOT_STREAM: u64 = 3;
+const LINK_STREAM: u64 = 4;
+
+/// The stream that deals the pillars for the game with this seed.
+pub fn gameplay_rng(seed: u64) -> GameRng {
+    let mut rng = GameRng::seed_from_u64(seed);
+    rng.set_stream(GAMEPLAY_STREAM);
+    rng
+}
+
+/// The stream for rows of garbage and the colors garbage turns into,
+/// so boards that take different amounts of it are still dealt the same pillars.
+pub fn garbage_rng(seed: u64) -> GameRng {
+    let mut rng = GameRng::seed_from_u64(seed);
+    rng.set_stream(GARBAGE_STREAM);
+    rng
+}
+
+/// The stream for puffs and anything else that doesn't change the game,
+/// so drawing more or fewer of them can't change which pillars come next.
+pub fn effects_rng(seed: u64) -> GameRng {
+    let mut rng = GameRng::seed_from_u64(seed);
+    rng.set_stream(EFFECTS_STREAM);
+    rng
+}
+
+/// The stream for a bot's mistakes, so they have nothing to do with the pillars it is dealt.
+pub fn bot_rng(seed: u64) -> GameRng {
+    let mut rng = GameRng::seed_from_u64(seed);
+    rng.set_stream(BOT_STREAM);
+    rng
+}
+
+/// The stream for the packets a test link loses, so they have nothing to do with the pillars.
+pub fn link_rng(seed: u64) -> GameRng {
+    let mut rng = GameRng::seed_from_u64(seed);
+    rng.set_stream(LINK_STREAM);
+    rng
+}
+
+/// What the simulation is busy doing this frame.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Phase {
+    Falling,
+    Landed {
+        /// time left until pillar is fixed
+        grace_period: Timer,
+    },
+    Matching {
+        timer: Timer,
+        h_s: f32,
+        current_matches: Vec<([usize; 2], Block)>,
+    },
+    Exploding {
+        timer: Timer,
+    },
+    GameOver {
+        ending: Ending,
+    },
+}
+
+/// Why a game finished.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Ending {
+    ToppedOut,
+    /// a time attack ran out of time
+    TimeUp,
+    /// a sprint cleared all its blocks, or a puzzle was solved
+    Finished,
+    /// a puzzle was still unsolved after its last pillar
+    OutOfPillars,
+}
+
+/// Things that happened during a call to `Simulation::update`,
+/// for the presentation layer to turn into sounds, puffs and score.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SimEvent {
+    PillarLanded,
+    Held,
+    HardDropped {
+        cells: u64,
+        points: u64,
+    },
+    LevelUp(u64),
+    BlocksCleared(Vec<([usize; 2], Block)>),
+    Scored(ScoreBreakdown),
+    /// a rising floor or an opponent pushed the stack up
+    FloorRose,
+    /// the board settled after `chain` clears in a row of `blocks` blocks in all
+    ChainFinished {
+        chain: u64,
+        blocks: u64,
+    },
+    GameOver,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PilPos {
+    pub x: usize,
+    pub y: f32,
+}
+
+impl From<Vec2> for PilPos {
+    fn from(v: Vec2) -> Self {
+        Self {
+            x: v.x as usize,
+            y: v.y,
+        }
+    }
+}
+
+impl From<PilPos> for Vec2 {
+    fn from(p: PilPos) -> Self {
+        vec2(p.x as f32, p.y)
+    }
+}
+
+impl From<PilPos> for (usize, f32) {
+    fn from(PilPos { x, y }: PilPos) -> Self {
+        (x, y)
+    }
+}
+
+pub fn pilpos(x: usize, y: f32) -> PilPos {
+    PilPos { x, y }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CommonState {
+    /// deals the pillars, and starting from it again plays the same game
+    pub seed: u64,
+    pub rules: GameRules,
+    pub board: Board,
+    /// upcoming pillars, the next one first
+    pub next_pillars: VecDeque<Pillar>,
+    pub palette: Palette,
+    pub level: u64,
+    // pub pillar_spawn_x: usize,
+    // pub pillar_spawn_y: f32,
+    // pub matches_required: u64,
+    //    pub pillar_sz: usize,
+    pub drop_count: u64,
+    pub pillars_dealt: u64,
+    pub held_pillar: Option<Pillar>,
+    /// how the points for the last clear were made up
+    pub last_score: Option<ScoreBreakdown>,
+    // pub drops_per_level: u64,
+    pub pillar_fall_rate: f32,
+    // pub fall_rate_increment: f32,
+    // pub fall_rate_max: f32,
+    /// seconds played, not counting pauses
+    pub clock: f32,
+    pub blocks_cleared: u64,
+    /// rows pushed up by a rising floor
+    pub floor_rises: u64,
+    /// a hint was asked for, so the game is marked in the high scores
+    pub hinted: bool,
+}
+
+pub fn increase_level(common: &mut CommonState) {
+    common.level += 1;
+    match common.level % 3 {
+        0 | 1 => {
+            common.pillar_fall_rate = (common.pillar_fall_rate + common.rules.fall_rate_increment)
+                .min(common.rules.max_fall_rate);
+        }
+        2 => {
+            common.palette.expand();
+        }
+        _ => {}
+    }
+}
+
+/// The rules of the game without any windows, sounds or clocks attached.
+/// Advances by an explicit time step and the commands in an `InputState`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Simulation {
+    pub common: CommonState,
+    pub current_pillar: Pillar,
+    pub pillar_pos: PilPos,
+    pub phase: Phase,
+    pub score: u64,
+    /// blocks cleared since the pillar landed
+    pub match_count: u64,
+    /// clears in a row since the pillar landed
+    pub chain: u64,
+    /// the pillar has already been swapped with the hold slot this drop
+    pub hold_used: bool,
+    /// every input so far, to play the game again, kept only for single player games
+    pub replay: Option<Replay>,
+    /// rows of garbage sent over by an opponent, pushed up before the next pillar
+    pub incoming_garbage: u64,
+    rng: GameRng,
+    garbage_rng: GameRng,
+    randomizer: AnyRandomizer,
+    held_down_flag: bool,
+    rot_cooldown: f32,
+    rot_timer: Timer,
+    horizontal_move_timer: Timer,
+    horizontal_move_cooldown: f32,
+}
+
+impl Simulation {
+    pub fn new_game(rules: GameRules, seed: u64) -> Self {
+        let mut rng = gameplay_rng(seed);
+        let mut randomizer = rules.randomizer.build();
+        let (board, mut pillars) = match rules.puzzle() {
+            Some(puzzle) => (
+                puzzle
+                    .board(&rules)
+                    .expect("puzzle boards are checked by validate when rules are loaded"),
+                puzzle.pillars.iter().cloned().collect::<VecDeque<_>>(),
+            ),
+            None => {
+                let preview_len = rules.preview_len.max(1) as u64;
+                let pillars = (1..=preview_len + 1)
+                    .map(|n| {
+                        new_pillar(&mut randomizer, &mut rng, &rules, &rules.initial_palette, n)
+                    })
+                    .collect();
+                let sz = [rules.board_sz[0], rules.board_sz[1] + rules.pillar_sz + 1];
+                (Board::new(sz), pillars)
+            }
+        };
+        let pillars_dealt = pillars.len() as u64;
+        let current_pillar = pillars.pop_front().unwrap();
+        let pillar_pos = rules.pillar_spawn_pt;
+        let palette = rules.initial_palette.clone();
+        let pillar_fall_rate = rules.initial_fall_rate;
+        let common = CommonState {
+            seed,
+            rules,
+            board,
+            next_pillars: pillars,
+            palette,
+            level: 1,
+            drop_count: 0,
+            pillars_dealt,
+            held_pillar: None,
+            last_score: None,
+            pillar_fall_rate,
+            clock: 0.0,
+            blocks_cleared: 0,
+            floor_rises: 0,
+            hinted: false,
+        };
+
+        Self {
+            common,
+            current_pillar,
+            pillar_pos,
+            phase: Phase::Falling,
+            score: 0,
+            match_count: 0,
+            chain: 0,
+            hold_used: false,
+            replay: None,
+            incoming_garbage: 0,
+            rng,
+            garbage_rng: garbage_rng(seed),
+            randomizer,
+            held_down_flag: true,
+            rot_cooldown: 0.2,
+            rot_timer: Timer::new(0.0),
+            horizontal_move_timer: Timer::new(0.0),
+            horizontal_move_cooldown: 0.1,
+        }
+    }
+
+    /// Where the falling pillar would come to rest, and the cells
+    /// that would match when it did if the rules ask for them to be marked.
+    pub fn ghost(&self) -> Option<(PilPos, Vec<[usize; 2]>)> {
+        let style = self.common.rules.ghost_pillar;
+        if !matches!(self.phase, Phase::Falling) || style == GhostPillar::Hidden {
+            return None;
+        }
+        let pos = resting_pos(&self.common.board, self.pillar_pos);
+        let marked = if style == GhostPillar::MarkMatches && !self.current_pillar.is_magic() {
+            let mut board = self.common.board.clone();
+            write_pillar(&mut board, &self.current_pillar, pos);
+            find_matches(&board, &self.common.rules)
+                .into_iter()
+                .map(|(idx, _)| idx)
+                .collect()
+        } else {
+            vec![]
+        };
+        Some((pos, marked))
+    }
+
+    pub fn is_game_over(&self) -> bool {
+        matches!(self.phase, Phase::GameOver { .. })
+    }
+
+    /// Queue up rows of garbage, for when a versus opponent makes a chain.
+    pub fn send_garbage(&mut self, rows: u64) {
+        self.incoming_garbage += rows;
+    }
+
+    /// Keep every input from now on, so the game can be played again.
+    /// Start before the first update, a replay has to begin with the game.
+    pub fn record_replay(&mut self) {
+        self.replay = Some(Replay::new(self.common.seed, self.common.rules.clone()));
+    }
+
+    /// Advance the game by `time_delta` seconds.
+    pub fn update<I>(
+        &mut self,
+        time_delta: f32,
+        input_state: &InputState<I, PlayerCommand>,
+    ) -> Vec<SimEvent>
+    where
+        I: Copy + Eq + Hash,
+    {
+        if let Some(replay) = &mut self.replay {
+            replay.record(time_delta, input_state);
+        }
+        if !self.is_game_over() {
+            self.common.clock += time_delta;
+        }
+        let mut events = vec![];
+        match self.phase {
+            Phase::Falling => {
+                if self.update_falling(time_delta, input_state, &mut events) {
+                    // hard drops are fixed in place straight away
+                    self.update_landed(0.0, input_state, &mut events);
+                }
+            }
+            Phase::Landed { .. } => self.update_landed(time_delta, input_state, &mut events),
+            Phase::Matching { .. } => self.update_matching(time_delta, &mut events),
+            Phase::Exploding { .. } => self.update_exploding(time_delta, &mut events),
+            Phase::GameOver { .. } => {}
+        }
+        self.check_goal(&mut events);
+        events
+    }
+
+    /// End time attacks when the clock runs out, and sprints when enough blocks are gone.
+    fn check_goal(&mut self, events: &mut Vec<SimEvent>) {
+        if self.is_game_over() {
+            return;
+        }
+        match self.common.rules.mode {
+            GameMode::Marathon => {}
+            GameMode::TimeAttack { seconds } => {
+                if seconds as f32 <= self.common.clock {
+                    self.common.clock = seconds as f32;
+                    self.end(Ending::TimeUp, events);
+                }
+            }
+            GameMode::Sprint { blocks } => {
+                if blocks <= self.common.blocks_cleared {
+                    self.end(Ending::Finished, events);
+                }
+            }
+            // these are checked once the board settles, see `next_turn`
+            GameMode::Puzzle | GameMode::RisingFloor { .. } => {}
+        }
+    }
+
+    /// Bring on the next pillar, unless a puzzle has been solved or has run out of them.
+    fn next_turn(&mut self, events: &mut Vec<SimEvent>) {
+        if self.rise_floor(events) {
+            return;
+        }
+        if let Some(puzzle) = self.common.rules.puzzle() {
+            if puzzle.goal.is_met(&self.common.board, self.chain) {
+                self.end(Ending::Finished, events);
+                return;
+            }
+            if self.common.next_pillars.is_empty() {
+                self.end(Ending::OutOfPillars, events);
+                return;
+            }
+        }
+        self.spawn_next_pillar();
+    }
+
+    fn end(&mut self, ending: Ending, events: &mut Vec<SimEvent>) {
+        self.phase = Phase::GameOver { ending };
+        events.push(SimEvent::GameOver);
+    }
+
+    /// Push up the rows of garbage a rising floor is due and any sent by an opponent,
+    /// which waits for the board to settle.
+    /// Returns true if that made matches or topped out the board.
+    fn rise_floor(&mut self, events: &mut Vec<SimEvent>) -> bool {
+        let common = &mut self.common;
+        let due = match common.rules.mode {
+            GameMode::RisingFloor {
+                every: RiseEvery::Seconds(seconds),
+            } => (common.clock / seconds as f32) as u64,
+            GameMode::RisingFloor {
+                every: RiseEvery::Drops(drops),
+            } => common.drop_count / drops,
+            _ => common.floor_rises,
+        };
+        let rows = due.saturating_sub(common.floor_rises) + self.incoming_garbage;
+        if rows == 0 {
+            return false;
+        }
+        for _ in 0..rows {
+            let row = garbage_row(&mut self.garbage_rng, common);
+            push_up(&mut common.board, &row);
+        }
+        common.floor_rises = common.floor_rises.max(due);
+        self.incoming_garbage = 0;
+        events.push(SimEvent::FloorRose);
+
+        let current_matches = find_matches(&common.board, &common.rules);
+        if !current_matches.is_empty() {
+            // a new chain, the pillar that set off the last one is long gone
+            self.match_count = 0;
+            self.chain = 0;
+            self.phase = Phase::Matching {
+                timer: Timer::new(4f32.recip()),
+                h_s: 0.0,
+                current_matches,
+            };
+            return true;
+        }
+        if is_game_over(&common.board, common.rules.pillar_sz) {
+            self.end(Ending::ToppedOut, events);
+            return true;
+        }
+        false
+    }
+
+    fn spawn_next_pillar(&mut self) {
+        let common = &mut self.common;
+        // puzzles only have the pillars they came with
+        if common.rules.mode != GameMode::Puzzle {
+            common.pillars_dealt += 1;
+            common.next_pillars.push_back(new_pillar(
+                &mut self.randomizer,
+                &mut self.rng,
+                &common.rules,
+                &common.palette,
+                common.pillars_dealt,
+            ));
+        }
+        self.current_pillar = common.next_pillars.pop_front().unwrap();
+        self.hold_used = false;
+        self.pillar_pos = common.rules.pillar_spawn_pt;
+        self.held_down_flag = true;
+        self.rot_timer = Timer::new(0.0);
+        self.horizontal_move_timer = Timer::new(0.0);
+        self.phase = Phase::Falling;
+    }
+
+    fn rotate<I>(&mut self, time_delta: f32, input_state: &InputState<I, PlayerCommand>)
+    where
+        I: Copy + Eq + Hash,
+    {
+        if self.rot_timer.update(time_delta) {
+            if input_state.just_active(PlayerCommand::RotUp) {
+                self.current_pillar.rot_up();
+                self.rot_timer.set(self.rot_cooldown);
+            } else if input_state.just_active(PlayerCommand::RotDown) {
+                self.current_pillar.rot_down();
+                self.rot_timer.set(self.rot_cooldown);
+            }
+        }
+    }
+
+    /// Put the current pillar in the hold slot, and bring back the one
+    /// that was there before or the next pillar if the slot was empty.
+    fn hold(&mut self, events: &mut Vec<SimEvent>) {
+        let held = self.common.held_pillar.take();
+        let current = self.current_pillar.clone();
+        match held {
+            Some(held) => {
+                self.current_pillar = held;
+                self.pillar_pos = self.common.rules.pillar_spawn_pt;
+                self.held_down_flag = true;
+            }
+            None => self.spawn_next_pillar(),
+        }
+        self.common.held_pillar = Some(current);
+        self.hold_used = true;
+        events.push(SimEvent::Held);
+    }
+
+    /// Returns true if the pillar was hard dropped.
+    fn update_falling<I>(
+        &mut self,
+        time_delta: f32,
+        input_state: &InputState<I, PlayerCommand>,
+        events: &mut Vec<SimEvent>,
+    ) -> bool
+    where
+        I: Copy + Eq + Hash,
+    {
+        let can_hold = self.common.held_pillar.is_some() || !self.common.next_pillars.is_empty();
+        if self.common.rules.hold_enabled
+            && !self.hold_used
+            && can_hold
+            && input_state.just_active(PlayerCommand::Hold)
+        {
+            self.hold(events);
+            return false;
+        }
+
+        if self.held_down_flag && input_state.inactive(PlayerCommand::MoveDown) {
+            self.held_down_flag = false;
+        }
+
+        self.rotate(time_delta, input_state);
+
+        let (left, right) = if self.horizontal_move_timer.update(time_delta) {
+            (
+                input_state.active(PlayerCommand::MoveLeft),
+                input_state.active(PlayerCommand::MoveRight),
+            )
+        } else {
+            (
+                input_state.just_active(PlayerCommand::MoveLeft),
+                input_state.just_active(PlayerCommand::MoveRight),
+            )
+        };
+        let dx = if left && !input_state.active(PlayerCommand::MoveRight) {
+            -1
+        } else if !input_state.active(PlayerCommand::MoveLeft) && right {
+            1
+        } else {
+            0
+        };
+        let new_pillar_x = self
+            .common
+            .rules
+            .grid()
+            .horizontal_step(self.pillar_pos.x, dx, self.common.board.x_len())
+            .unwrap_or(self.pillar_pos.x);
+        if is_horizontal_move_valid(new_pillar_x, self.pillar_pos.y, &self.common.board) {
+            if new_pillar_x != self.pillar_pos.x {
+                self.horizontal_move_timer
+                    .set(self.horizontal_move_cooldown);
+            }
+            self.pillar_pos.x = new_pillar_x;
+        }
+
+        if input_state.just_active(PlayerCommand::HardDrop) {
+            let resting_pos = resting_pos(&self.common.board, self.pillar_pos);
+            let cells = (resting_pos.y as usize).saturating_sub(self.pillar_pos.y as usize) as u64;
+            let points = scoring::calculate_drop_bonus(cells, self.common.rules.hard_drop_bonus);
+            self.score += points;
+            self.pillar_pos = resting_pos;
+            self.phase = Phase::Landed {
+                grace_period: Timer::new(0.0),
+            };
+            events.push(SimEvent::HardDropped { cells, points });
+            events.push(SimEvent::PillarLanded);
+            return true;
+        }
+
+        let next_pillar_y = self.pillar_pos.y
+            + if input_state.active(PlayerCommand::MoveDown) && !self.held_down_flag {
+                time_delta * self.common.rules.max_fall_rate
+            } else {
+                time_delta
+                    * self
+                        .common
+                        .pillar_fall_rate
+                        .min(self.common.rules.max_fall_rate)
+            };
+
+        if let BoardCell::Empty = self.common.board.get_pp(PilPos {
+            x: self.pillar_pos.x,
+            y: next_pillar_y,
+        }) {
+            self.pillar_pos.y = next_pillar_y;
+        } else {
+            self.pillar_pos.y = self.pillar_pos.y.ceil() - 0.0001;
+            self.phase = Phase::Landed {
+                grace_period: Timer::new(0.3),
+            };
+            events.push(SimEvent::PillarLanded);
+        }
+        false
+    }
+
+    fn update_landed<I>(
+        &mut self,
+        time_delta: f32,
+        input_state: &InputState<I, PlayerCommand>,
+        events: &mut Vec<SimEvent>,
+    ) where
+        I: Copy + Eq + Hash,
+    {
+        self.rotate(time_delta, input_state);
+
+        let fixed = match &mut self.phase {
+            Phase::Landed { grace_period } => grace_period.update(time_delta),
+            _ => false,
+        };
+        if !fixed {
+            return;
+        }
+
+        if !self.current_pillar.is_magic() {
+            write_pillar(
+                &mut self.common.board,
+                &self.current_pillar,
+                self.pillar_pos,
+            );
+        }
+
+        self.common.drop_count += 1;
+        if self
+            .common
+            .drop_count
+            .is_multiple_of(self.common.rules.drops_per_level)
+        {
+            increase_level(&mut self.common);
+            events.push(SimEvent::LevelUp(self.common.level));
+        }
+
+        if self.current_pillar.is_magic() {
+            self.land_magic_pillar(events);
+            return;
+        }
+
+        let current_matches = find_matches(&self.common.board, &self.common.rules);
+        self.match_count = 0;
+        self.chain = 0;
+        if !current_matches.is_empty() {
+            self.phase = Phase::Matching {
+                timer: Timer::new(4f32.recip()),
+                h_s: 0.0,
+                current_matches,
+            };
+        } else if is_game_over(&self.common.board, self.common.rules.pillar_sz) {
+            self.end(Ending::ToppedOut, events);
+        } else {
+            self.next_turn(events);
+        }
+    }
+
+    /// Clear every block with the color of the one the magic pillar landed on.
+    /// The blocks left behind then fall and cascade as usual.
+    fn land_magic_pillar(&mut self, events: &mut Vec<SimEvent>) {
+        let PilPos { x, y } = self.pillar_pos;
+        let board = &mut self.common.board;
+        let mut cleared = vec![];
+        if let BoardCell::Contains(color) = board.get([x, y as usize + 1]) {
+            for x in 0..board.x_len() {
+                for y in 0..board.y_len() {
+                    if board[[x, y]] == Some(color) {
+                        board[[x, y]] = None;
+                        cleared.push(([x, y], color));
+                    }
+                }
+            }
+        }
+        self.match_count = cleared.len() as u64;
+        self.chain = 0;
+        self.common.blocks_cleared += cleared.len() as u64;
+        if !cleared.is_empty() {
+            let points = scoring::calculate_magic_points(cleared.len() as u64, self.common.level);
+            self.score_points(points, events);
+            events.push(SimEvent::BlocksCleared(cleared));
+            // anything the magic shakes loose continues the chain
+            self.chain = 1;
+        }
+        self.phase = Phase::Exploding {
+            timer: Timer::new(0.2),
+        };
+    }
+
+    fn update_matching(&mut self, time_delta: f32, events: &mut Vec<SimEvent>) {
+        if let Phase::Matching {
+            timer,
+            h_s,
+            current_matches,
+        } = &mut self.phase
+        {
+            if timer.update(time_delta) {
+                let mut current_matches = std::mem::take(current_matches);
+                let groups = count_groups(&current_matches, &self.common.board, &self.common.rules);
+                let grid = self.common.rules.grid();
+                let garbage = garbage_next_to(&self.common.board, &current_matches, grid);
+                for &(idx, _) in current_matches.iter() {
+                    self.common.board[idx] = None;
+                }
+                for idx in garbage {
+                    if self.common.rules.garbage_rows == GarbageRows::Turning {
+                        let block = self.common.palette.get_random(&mut self.garbage_rng);
+                        self.common.board[idx] = Some(block);
+                    } else {
+                        self.common.board[idx] = None;
+                        current_matches.push((idx, Block::Garbage));
+                    }
+                }
+                self.chain += 1;
+                self.match_count += current_matches.len() as u64;
+                self.common.blocks_cleared += current_matches.len() as u64;
+                let points = scoring::calculate_points(
+                    current_matches.len() as u64,
+                    groups,
+                    self.chain,
+                    self.common.level,
+                );
+                self.score_points(points, events);
+                events.push(SimEvent::BlocksCleared(current_matches));
+                self.phase = Phase::Exploding {
+                    timer: Timer::new(0.2),
+                };
+            } else {
+                *h_s += 3.0 * time_delta;
+            }
+        }
+    }
+
+    fn update_exploding(&mut self, time_delta: f32, events: &mut Vec<SimEvent>) {
+        let elapsed = match &mut self.phase {
+            Phase::Exploding { timer } => timer.update(time_delta),
+            _ => false,
+        };
+        if !elapsed {
+            return;
+        }
+
+        if fall_down(&mut self.common.board) {
+            let current_matches = find_matches(&self.common.board, &self.common.rules);
+            if !current_matches.is_empty() {
+                self.phase = Phase::Matching {
+                    timer: Timer::new(4f32.recip()),
+                    h_s: 0.0,
+                    current_matches,
+                };
+            }
+        } else {
+            if 0 < self.chain {
+                events.push(SimEvent::ChainFinished {
+                    chain: self.chain,
+                    blocks: self.match_count,
+                });
+            }
+            if is_game_over(&self.common.board, self.common.rules.pillar_sz) {
+                // all blocks have already fallen, no new matches
+                self.end(Ending::ToppedOut, events);
+            } else {
+                self.next_turn(events);
+            }
+        }
+    }
+
+    /// Each step of a chain is scored as it clears, before the board settles.
+    /// A chain that leaves the stack topped out keeps its points,
+    /// so the final score is the one shown when the game ended.
+    fn score_points(&mut self, points: ScoreBreakdown, events: &mut Vec<SimEvent>) {
+        self.score += points.total();
+        self.common.last_score = Some(points);
+        events.push(SimEvent::Scored(points));
+    }
+}
+
+/// The `pillar_number`th pillar dealt this game,
+/// which is magic every `magic_pillar_interval` pillars.
+fn new_pillar<R: Rng>(
+    randomizer: &mut dyn Randomizer,
+    rng: &mut R,
+    rules: &GameRules,
+    palette: &Palette,
+    pillar_number: u64,
+) -> Pillar {
+    match rules.magic_pillar_interval {
+        Some(interval) if pillar_number.is_multiple_of(interval) => {
+            Pillar::new_magic(rules.pillar_sz)
+        }
+        _ => randomizer.next_pillar(rng, palette, rules.pillar_sz),
+    }
+}
+
+/// A row for the bottom of a rising floor, with one gap left in it.
+fn garbage_row<R: Rng>(rng: &mut R, common: &CommonState) -> Vec<Option<Block>> {
+    let width = common.board.x_len();
+    let gap = rng.gen_range(0..width);
+    (0..width)
+        .map(|x| {
+            if x == gap {
+                None
+            } else if common.rules.garbage_rows == GarbageRows::Colors {
+                Some(common.palette.get_random(rng))
+            } else {
+                Some(Block::Garbage)
+            }
+        })
+        .collect()
+}
+
+pub fn is_horizontal_move_valid(new_pillar_x: usize, pillar_y: f32, board: &Board) -> bool {
+    new_pillar_x < board.x_len()
+        && (pillar_y < 0.0 || {
+            let y_idx = pillar_y as usize;
+            board[[new_pillar_x, y_idx]].is_none()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pilpos, Ending, Phase, SimEvent, Simulation};
+    use crate::board::{assert_board, Block, Board};
+    use crate::input::{default_input_cfg, InputState, PlayerCommand};
+    use crate::pillar::Pillar;
+    use crate::the_rules::{GameMode, GameRules, GarbageRows, GhostPillar, RiseEvery, Topology};
+    use crate::timer::Timer;
+
+    #[test]
+    fn an_idle_game_runs_headless_until_it_is_over() {
+        let mut sim = Simulation::new_game(GameRules::default(), 7);
+        let input_state = InputState::new(default_input_cfg());
+        let mut events = vec![];
+        for _ in 0..100_000 {
+            events.extend(sim.update(1.0 / 60.0, &input_state));
+            if sim.is_game_over() {
+                break;
+            }
+        }
+        assert!(matches!(
+            sim.phase,
+            Phase::GameOver {
+                ending: Ending::ToppedOut
+            }
+        ));
+        assert_eq!(events.last(), Some(&SimEvent::GameOver));
+        assert!(events.contains(&SimEvent::PillarLanded));
+    }
+
+    #[test]
+    fn a_time_attack_ends_when_the_clock_runs_out() {
+        let rules = GameRules {
+            mode: GameMode::TimeAttack { seconds: 2 },
+            ..Default::default()
+        };
+        let mut sim = Simulation::new_game(rules, 7);
+        let input_state = InputState::new(default_input_cfg());
+        let mut events = vec![];
+        for _ in 0..600 {
+            events.extend(sim.update(1.0 / 60.0, &input_state));
+        }
+        assert!(matches!(
+            sim.phase,
+            Phase::GameOver {
+                ending: Ending::TimeUp
+            }
+        ));
+        assert_eq!(sim.common.clock, 2.0);
+        assert_eq!(
+            events.iter().filter(|&e| e == &SimEvent::GameOver).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn a_seed_always_deals_the_same_pillars() {
+        let deal = |seed| {
+            let mut sim = Simulation::new_game(GameRules::default(), seed);
+            let input_state = InputState::new(default_input_cfg());
+            let mut pillars = vec![];
+            for _ in 0..10_000 {
+                sim.update(1.0 / 60.0, &input_state);
+                if sim.is_game_over() {
+                    break;
+                }
+                if pillars.last() != Some(&sim.current_pillar) {
+                    pillars.push(sim.current_pillar.clone());
+                }
+            }
+            pillars
+        };
+        assert_eq!(deal(7), deal(7));
+        assert_ne!(deal(7), deal(8));
+    }
+
+    #[test]
+    fn pillar_moves_on_commands() {
+        let mut sim = Simulation::new_game(GameRules::default(), 7);
+        let mut input_state = InputState::new(default_input_cfg());
+        let start_x = sim.pillar_pos.x;
+        sim.update(1.0 / 60.0, &input_state);
+        input_state.save_current();
+        input_state.activate_command(PlayerCommand::MoveLeft);
+        sim.update(1.0 / 60.0, &input_state);
+        assert_eq!(sim.pillar_pos.x, start_x - 1);
+    }
+
+    #[test]
+    fn pillar_wraps_round_a_cylinder() {
+        let rules = GameRules {
+            topology: Topology::Cylinder,
+            pillar_spawn_pt: pilpos(0, 3.0),
+            ..Default::default()
+        };
+        let mut sim = Simulation::new_game(rules, 7);
+        let mut input_state = InputState::new(default_input_cfg());
+        sim.update(1.0 / 60.0, &input_state);
+        input_state.save_current();
+        input_state.activate_command(PlayerCommand::MoveLeft);
+        sim.update(1.0 / 60.0, &input_state);
+        assert_eq!(sim.pillar_pos.x, sim.common.board.x_len() - 1);
+    }
+
+    #[test]
+    fn magic_pillar_clears_the_color_it_lands_on() {
+        let mut sim = Simulation::new_game(GameRules::default(), 7);
+        let bottom = sim.common.board.y_len() - 1;
+        sim.common.board = Board::from_bottom_rows(sim.common.board.sz(), "RG.R...").unwrap();
+        sim.current_pillar = Pillar::new_magic(3);
+        sim.pillar_pos = pilpos(3, bottom as f32 - 0.0001);
+        sim.phase = Phase::Landed {
+            grace_period: Timer::new(0.0),
+        };
+        let input_state = InputState::new(default_input_cfg());
+        let events = sim.update(1.0 / 60.0, &input_state);
+        assert_board!(sim.common.board, ".G.....");
+        assert!(events.iter().any(|e| matches!(e, SimEvent::Scored(_))));
+    }
+
+    #[test]
+    fn hard_drop_lands_and_fixes_the_pillar_in_one_frame() {
+        let mut sim = Simulation::new_game(GameRules::default(), 7);
+        let mut input_state = InputState::new(default_input_cfg());
+        let x = sim.pillar_pos.x;
+        let bottom = sim.common.board.y_len() - 1;
+        sim.update(1.0 / 60.0, &input_state);
+        input_state.save_current();
+        input_state.activate_command(PlayerCommand::HardDrop);
+        let events = sim.update(1.0 / 60.0, &input_state);
+        assert_eq!(sim.common.drop_count, 1);
+        assert!(sim.common.board[[x, bottom]].is_some());
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, SimEvent::HardDropped { .. })));
+        assert!(matches!(sim.phase, Phase::Falling));
+    }
+
+    #[test]
+    fn ghost_lands_on_the_stack_and_marks_matches() {
+        let rules = GameRules {
+            ghost_pillar: GhostPillar::MarkMatches,
+            ..Default::default()
+        };
+        let mut sim = Simulation::new_game(rules, 7);
+        let x = sim.pillar_pos.x;
+        let bottom = sim.common.board.y_len() - 1;
+        sim.common.board[[x, bottom]] = Some(Block::Red);
+        sim.common.board[[x, bottom - 1]] = Some(Block::Red);
+        sim.current_pillar = Pillar::Blocks(vec![Block::Green, Block::Blue, Block::Red]);
+        let (pos, marked) = sim.ghost().unwrap();
+        assert_eq!(pos.y as usize, bottom - 2);
+        assert_eq!(marked.len(), 3);
+        assert!(marked.contains(&[x, bottom]));
+    }
+
+    #[test]
+    fn hold_swaps_once_per_drop() {
+        let mut sim = Simulation::new_game(GameRules::default(), 7);
+        let mut input_state = InputState::new(default_input_cfg());
+        let first = sim.current_pillar.clone();
+        let next = sim.common.next_pillars[0].clone();
+        sim.update(1.0 / 60.0, &input_state);
+        input_state.save_current();
+        input_state.activate_command(PlayerCommand::Hold);
+        sim.update(1.0 / 60.0, &input_state);
+        assert_eq!(sim.current_pillar, next);
+        assert_eq!(sim.common.held_pillar, Some(first));
+        assert_eq!(sim.pillar_pos.y, sim.common.rules.pillar_spawn_pt.y);
+
+        input_state.save_current();
+        input_state.deactivate_command(PlayerCommand::Hold);
+        sim.update(1.0 / 60.0, &input_state);
+        input_state.save_current();
+        input_state.activate_command(PlayerCommand::Hold);
+        sim.update(1.0 / 60.0, &input_state);
+        assert_eq!(sim.current_pillar, next);
+    }
+
+    /// Hard drop the current pillar where it is and let the board settle.
+    fn hard_drop_and_settle(sim: &mut Simulation) -> Vec<SimEvent> {
+        let mut input_state = InputState::new(default_input_cfg());
+        let mut events = sim.update(1.0 / 60.0, &input_state);
+        input_state.save_current();
+        input_state.activate_command(PlayerCommand::HardDrop);
+        events.extend(sim.update(1.0 / 60.0, &input_state));
+        input_state.save_current();
+        input_state.deactivate_command(PlayerCommand::HardDrop);
+        for _ in 0..600 {
+            events.extend(sim.update(1.0 / 60.0, &input_state));
+            if matches!(sim.phase, Phase::Falling) {
+                break;
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn garbage_breaks_next_to_a_match() {
+        let mut sim = Simulation::new_game(GameRules::default(), 7);
+        sim.common.board = Board::from_bottom_rows(sim.common.board.sz(), "#RR....").unwrap();
+        sim.current_pillar = Pillar::Blocks(vec![Block::Green, Block::Blue, Block::Red]);
+        sim.pillar_pos.x = 3;
+        hard_drop_and_settle(&mut sim);
+        assert_board!(sim.common.board, "...G...\n...B...");
+        assert_eq!(sim.common.blocks_cleared, 4);
+    }
+
+    #[test]
+    fn a_chain_that_tops_out_keeps_its_points() {
+        let mut sim = Simulation::new_game(GameRules::default(), 7);
+        // the left column reaches the top, the right one clears under the pillar
+        let rows: Vec<String> = (3..sim.common.board.y_len())
+            .map(|y| {
+                let mut row = ['.'; 7];
+                row[2] = if y % 2 == 1 { 'O' } else { 'P' };
+                row[3] = match y {
+                    3 => '.',
+                    4 | 5 => 'R',
+                    _ if y % 2 == 0 => 'G',
+                    _ => 'B',
+                };
+                row.iter().collect()
+            })
+            .collect();
+        sim.common.board =
+            Board::from_bottom_rows(sim.common.board.sz(), &rows.join("\n")).unwrap();
+        sim.current_pillar = Pillar::Blocks(vec![Block::Green, Block::Blue, Block::Red]);
+        sim.pillar_pos = pilpos(3, 3.0);
+        sim.phase = Phase::Landed {
+            grace_period: Timer::new(0.0),
+        };
+        let input_state = InputState::new(default_input_cfg());
+        let mut events = vec![];
+        for _ in 0..600 {
+            events.extend(sim.update(1.0 / 60.0, &input_state));
+            if sim.is_game_over() {
+                break;
+            }
+        }
+        assert!(matches!(
+            sim.phase,
+            Phase::GameOver {
+                ending: Ending::ToppedOut
+            }
+        ));
+        let scored: u64 = events
+            .iter()
+            .map(|e| match e {
+                SimEvent::Scored(points) => points.total(),
+                _ => 0,
+            })
+            .sum();
+        assert!(0 < scored);
+        assert_eq!(sim.score, scored);
+        assert_eq!(events.last(), Some(&SimEvent::GameOver));
+    }
+
+    #[test]
+    fn garbage_doesnt_change_the_pillars_dealt() {
+        let rules = GameRules {
+            garbage_rows: GarbageRows::Colors,
+            ..Default::default()
+        };
+        let mut sims = [
+            Simulation::new_game(rules.clone(), 7),
+            Simulation::new_game(rules, 7),
+        ];
+        sims[1].send_garbage(2);
+        for _ in 0..3 {
+            for sim in sims.iter_mut() {
+                hard_drop_and_settle(sim);
+            }
+        }
+        assert_ne!(sims[0].common.board, sims[1].common.board);
+        // the queue holds the pillars dealt after the garbage came up
+        assert_eq!(sims[0].current_pillar, sims[1].current_pillar);
+        assert_eq!(sims[0].common.next_pillars, sims[1].common.next_pillars);
+    }
+
+    #[test]
+    fn the_floor_rises_between_pillars() {
+        let rules = GameRules {
+            mode: GameMode::RisingFloor {
+                every: RiseEvery::Drops(1),
+            },
+            ..Default::default()
+        };
+        let mut sim = Simulation::new_game(rules, 7);
+        sim.current_pillar = Pillar::Blocks(vec![Block::Green, Block::Blue, Block::Red]);
+        let events = hard_drop_and_settle(&mut sim);
+        assert!(events.contains(&SimEvent::FloorRose));
+        assert_eq!(sim.common.floor_rises, 1);
+        let bottom = sim.common.board.y_len() - 1;
+        let garbage = (0..sim.common.board.x_len())
+            .filter(|&x| sim.common.board[[x, bottom]] == Some(Block::Garbage))
+            .count();
+        assert_eq!(garbage, sim.common.board.x_len() - 1);
+    }
+}
@@ -1,9 +1,212 @@
-use crate::{board::Palette, game_loop::PilPos};
+use std::{error::Error, fmt, io::Read};
 
-#[derive(Clone, Debug)]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{Block, Board, Palette, ParseBoardError},
+    grid::{Grid, HexGrid},
+    puzzle::Puzzle,
+    simulation::PilPos,
+};
+
+/// How blocks of the same color have to be arranged to be cleared.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum MatchRule {
+    /// any group of touching blocks
+    ConnectedGroup,
+    /// horizontal, vertical and diagonal lines, like Columns
+    Lines,
+}
+
+impl MatchRule {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ConnectedGroup => "groups",
+            Self::Lines => "lines",
+        }
+    }
+
+    pub fn toggle(self) -> Self {
+        match self {
+            Self::ConnectedGroup => Self::Lines,
+            Self::Lines => Self::ConnectedGroup,
+        }
+    }
+}
+
+/// Which cells count as touching when searching for groups.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Topology {
+    /// left, right, up and down
+    FourWay,
+    /// diagonals touch as well
+    EightWay,
+    /// four way, and the leftmost column touches the rightmost
+    Cylinder,
+    /// eight way, and the leftmost column touches the rightmost
+    EightWayCylinder,
+}
+
+impl Topology {
+    pub fn directions(self) -> &'static [[isize; 2]] {
+        match self {
+            Self::FourWay | Self::Cylinder => &[[1, 0], [0, 1], [-1, 0], [0, -1]],
+            Self::EightWay | Self::EightWayCylinder => &[
+                [1, 0],
+                [1, 1],
+                [0, 1],
+                [-1, 1],
+                [-1, 0],
+                [-1, -1],
+                [0, -1],
+                [1, -1],
+            ],
+        }
+    }
+
+    pub fn wraps(self) -> bool {
+        matches!(self, Self::Cylinder | Self::EightWayCylinder)
+    }
+}
+
+/// The shape of the cells on the board.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum BoardShape {
+    Square,
+    Hex,
+}
+
+/// Whether to show where the falling pillar will land.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum GhostPillar {
+    Hidden,
+    Shown,
+    /// also outline the blocks that would match when it lands
+    MarkMatches,
+}
+
+impl GhostPillar {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Hidden => "off",
+            Self::Shown => "on",
+            Self::MarkMatches => "matches",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Self::Hidden => Self::Shown,
+            Self::Shown => Self::MarkMatches,
+            Self::MarkMatches => Self::Hidden,
+        }
+    }
+}
+
+/// What ends a game, besides the board topping out.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum GameMode {
+    /// carry on until the board tops out
+    Marathon,
+    /// score as much as possible before the time runs out
+    TimeAttack { seconds: u64 },
+    /// clear this many blocks as quickly as possible
+    Sprint { blocks: u64 },
+    /// reach the goal of the rules' puzzle with the pillars it gives
+    Puzzle,
+    /// survive while rows of garbage push the stack up from below
+    RisingFloor { every: RiseEvery },
+}
+
+/// How often the floor rises in rising floor games.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum RiseEvery {
+    Seconds(u64),
+    Drops(u64),
+}
+
+/// What the rows pushed up by a rising floor are made of.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum GarbageRows {
+    /// random colors from the palette
+    Colors,
+    /// grey garbage that breaks when a match is made next to it
+    Breaking,
+    /// grey garbage that turns into a random color when a match is made next to it
+    Turning,
+}
+
+impl GameMode {
+    /// The modes offered on the title screen.
+    pub const CHOICES: [Self; 7] = [
+        Self::Marathon,
+        Self::TimeAttack { seconds: 120 },
+        Self::TimeAttack { seconds: 180 },
+        Self::TimeAttack { seconds: 300 },
+        Self::Sprint { blocks: 100 },
+        Self::RisingFloor {
+            every: RiseEvery::Seconds(10),
+        },
+        Self::RisingFloor {
+            every: RiseEvery::Drops(5),
+        },
+    ];
+
+    pub fn name(self) -> String {
+        match self {
+            Self::Marathon => "marathon".to_owned(),
+            Self::TimeAttack { seconds } => format!("time attack {}", format_clock(seconds as f32)),
+            Self::Sprint { blocks } => format!("sprint {}", blocks),
+            Self::Puzzle => "puzzle".to_owned(),
+            Self::RisingFloor {
+                every: RiseEvery::Seconds(seconds),
+            } => format!("rising every {}s", seconds),
+            Self::RisingFloor {
+                every: RiseEvery::Drops(drops),
+            } => format!("rising every {} drops", drops),
+        }
+    }
+}
+
+/// Minutes and seconds, and tenths when there are under ten minutes.
+pub fn format_clock(seconds: f32) -> String {
+    let tenths = (seconds * 10.0) as u64;
+    let (minutes, tenths) = (tenths / 600, tenths % 600);
+    if minutes < 10 {
+        format!("{}:{:02}.{}", minutes, tenths / 10, tenths % 10)
+    } else {
+        format!("{}:{:02}", minutes, tenths / 10)
+    }
+}
+
+/// How the colors of the pillars are picked, see `randomizer`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum RandomizerKind {
+    PureRandom,
+    /// the same number of each color from a shuffled bag
+    ColorBag,
+    /// never the same pillar twice in a row
+    NoRepeats,
+}
+
+/// Rule files only need to list what differs from the defaults.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
 pub struct GameRules {
+    /// shown on the title screen
+    pub name: String,
+    /// chosen on the title screen rather than in rule files, except for puzzles
+    pub mode: GameMode,
+    /// the board, pillars and goal of a puzzle, see `puzzle`
+    pub puzzle: Option<Puzzle>,
+    pub match_rule: MatchRule,
+    pub board_shape: BoardShape,
+    /// only used by square boards
+    pub topology: Topology,
     pub matches_required: u64,
     pub drops_per_level: u64,
+    /// every this many pillars one is magic, never if `None`
+    pub magic_pillar_interval: Option<u64>,
     pub pillar_sz: usize,
     pub board_sz: [usize; 2],
     pub initial_palette: Palette,
@@ -11,6 +214,16 @@ pub struct GameRules {
     pub initial_fall_rate: f32,
     pub fall_rate_increment: f32,
     pub max_fall_rate: f32,
+    /// points per row for hard dropping a pillar
+    pub hard_drop_bonus: u64,
+    pub ghost_pillar: GhostPillar,
+    /// the pillar can be swapped into a hold slot once per drop
+    pub hold_enabled: bool,
+    pub randomizer: RandomizerKind,
+    /// only used by rising floor games
+    pub garbage_rows: GarbageRows,
+    /// how many upcoming pillars are shown, at least one
+    pub preview_len: usize,
     pub rot_cooldown: f32,
     pub horizontal_move_cooldown: f32,
 }
@@ -19,8 +232,15 @@ impl Default for GameRules {
     fn default() -> Self {
         let pillar_sz = 3;
         Self {
+            name: "classic".to_owned(),
+            mode: GameMode::Marathon,
+            puzzle: None,
+            match_rule: MatchRule::ConnectedGroup,
+            board_shape: BoardShape::Square,
+            topology: Topology::FourWay,
             matches_required: 3,
             drops_per_level: 10,
+            magic_pillar_interval: None,
             pillar_sz,
             board_sz: [7, 16],
             initial_palette: Palette::new(3),
@@ -31,8 +251,283 @@ impl Default for GameRules {
             initial_fall_rate: 2.4,
             fall_rate_increment: 0.2,
             max_fall_rate: 25.0,
+            hard_drop_bonus: 2,
+            ghost_pillar: GhostPillar::Shown,
+            hold_enabled: true,
+            randomizer: RandomizerKind::PureRandom,
+            garbage_rows: GarbageRows::Breaking,
+            preview_len: 3,
             rot_cooldown: 0.15,
             horizontal_move_cooldown: 0.1,
         }
     }
 }
+
+/// Why a set of rules can't be played.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvalidRules {
+    EmptyBoard {
+        board_sz: [usize; 2],
+    },
+    PillarTooTall {
+        pillar_sz: usize,
+        board_height: usize,
+    },
+    SpawnOutsideBoard {
+        spawn_pt: PilPos,
+        board_sz: [usize; 2],
+        pillar_sz: usize,
+    },
+    EmptyPalette,
+    GarbageInPalette,
+    RepeatedColor(Block),
+    TooFewMatchesRequired(u64),
+    NoDropsPerLevel,
+    NoMagicPillarInterval,
+    FallRates {
+        initial: f32,
+        max: f32,
+    },
+    NoPreview,
+    NoGoal(GameMode),
+    NoRiseInterval,
+    NoPuzzlePillars,
+    PuzzlePillarSize {
+        pillar: usize,
+        len: usize,
+        pillar_sz: usize,
+    },
+    PuzzleBoard(ParseBoardError),
+}
+
+impl fmt::Display for InvalidRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyBoard { board_sz } => write!(
+                f,
+                "the board is {}x{}, it needs at least one column and one row",
+                board_sz[0], board_sz[1]
+            ),
+            Self::PillarTooTall {
+                pillar_sz,
+                board_height,
+            } => write!(
+                f,
+                "pillar_sz is {}, it must be at least 1 and smaller than the board height of {}",
+                pillar_sz, board_height
+            ),
+            Self::SpawnOutsideBoard {
+                spawn_pt,
+                board_sz,
+                pillar_sz,
+            } => write!(
+                f,
+                "pillar_spawn_pt ({}, {}) is outside the board, x must be below {} and y between {} and {}",
+                spawn_pt.x,
+                spawn_pt.y,
+                board_sz[0],
+                pillar_sz,
+                pillar_sz + 1
+            ),
+            Self::EmptyPalette => write!(f, "initial_palette has no colors"),
+            Self::GarbageInPalette => write!(f, "initial_palette can't deal Garbage"),
+            Self::RepeatedColor(block) => {
+                write!(f, "initial_palette has {:?} more than once", block)
+            }
+            Self::TooFewMatchesRequired(n) => {
+                write!(f, "matches_required is {}, it must be at least 2", n)
+            }
+            Self::NoDropsPerLevel => write!(f, "drops_per_level must be at least 1"),
+            Self::NoMagicPillarInterval => {
+                write!(f, "magic_pillar_interval must be at least 1, or None")
+            }
+            Self::FallRates { initial, max } => write!(
+                f,
+                "initial_fall_rate is {} and max_fall_rate is {}, both must be above 0 and the max at least the initial rate",
+                initial, max
+            ),
+            Self::NoPreview => write!(f, "preview_len must be at least 1"),
+            Self::NoGoal(mode) => write!(f, "mode {:?} has nothing to aim for", mode),
+            Self::NoRiseInterval => write!(f, "the floor must rise every 1 or more seconds or drops"),
+            Self::NoPuzzlePillars => write!(f, "the puzzle has no pillars"),
+            Self::PuzzlePillarSize {
+                pillar,
+                len,
+                pillar_sz,
+            } => write!(
+                f,
+                "pillar {} of the puzzle is {} long, it must be pillar_sz {}",
+                pillar, len, pillar_sz
+            ),
+            Self::PuzzleBoard(e) => write!(f, "the puzzle's board can't be read: {}", e),
+        }
+    }
+}
+
+impl Error for InvalidRules {}
+
+/// Read a set of rules in RON and check that they can be played.
+pub fn load_rules<R: Read>(reader: R) -> Result<GameRules, Box<dyn Error>> {
+    let rules: GameRules = ron::de::from_reader(reader)?;
+    rules.validate()?;
+    Ok(rules)
+}
+
+impl GameRules {
+    /// Check for values that would make a game impossible, or crash it.
+    pub fn validate(&self) -> Result<(), InvalidRules> {
+        let [width, height] = self.board_sz;
+        if width == 0 || height == 0 {
+            return Err(InvalidRules::EmptyBoard {
+                board_sz: self.board_sz,
+            });
+        }
+        if self.pillar_sz == 0 || height <= self.pillar_sz {
+            return Err(InvalidRules::PillarTooTall {
+                pillar_sz: self.pillar_sz,
+                board_height: height,
+            });
+        }
+        // the spawn point is the bottom of the pillar, in the rows above the board
+        let PilPos { x, y } = self.pillar_spawn_pt;
+        let (lowest, highest) = (self.pillar_sz as f32, (self.pillar_sz + 1) as f32);
+        if width <= x || y < lowest || highest < y {
+            return Err(InvalidRules::SpawnOutsideBoard {
+                spawn_pt: self.pillar_spawn_pt,
+                board_sz: self.board_sz,
+                pillar_sz: self.pillar_sz,
+            });
+        }
+        if self.initial_palette.blocks().is_empty() {
+            return Err(InvalidRules::EmptyPalette);
+        }
+        let palette = self.initial_palette.blocks();
+        if palette.iter().any(|block| block.is_garbage()) {
+            return Err(InvalidRules::GarbageInPalette);
+        }
+        for (i, block) in palette.iter().enumerate() {
+            if palette[..i].contains(block) {
+                return Err(InvalidRules::RepeatedColor(*block));
+            }
+        }
+        if self.matches_required < 2 {
+            return Err(InvalidRules::TooFewMatchesRequired(self.matches_required));
+        }
+        if self.drops_per_level == 0 {
+            return Err(InvalidRules::NoDropsPerLevel);
+        }
+        if self.magic_pillar_interval == Some(0) {
+            return Err(InvalidRules::NoMagicPillarInterval);
+        }
+        if !(0.0 < self.initial_fall_rate && self.initial_fall_rate <= self.max_fall_rate) {
+            return Err(InvalidRules::FallRates {
+                initial: self.initial_fall_rate,
+                max: self.max_fall_rate,
+            });
+        }
+        if self.preview_len == 0 {
+            return Err(InvalidRules::NoPreview);
+        }
+        if let GameMode::TimeAttack { seconds: 0 } | GameMode::Sprint { blocks: 0 } = self.mode {
+            return Err(InvalidRules::NoGoal(self.mode));
+        }
+        if let GameMode::RisingFloor {
+            every: RiseEvery::Seconds(0) | RiseEvery::Drops(0),
+        } = self.mode
+        {
+            return Err(InvalidRules::NoRiseInterval);
+        }
+        if self.mode == GameMode::Puzzle {
+            self.validate_puzzle()?;
+        }
+        Ok(())
+    }
+
+    fn validate_puzzle(&self) -> Result<(), InvalidRules> {
+        let puzzle = match &self.puzzle {
+            Some(puzzle) => puzzle,
+            None => return Err(InvalidRules::NoGoal(self.mode)),
+        };
+        if puzzle.pillars.is_empty() {
+            return Err(InvalidRules::NoPuzzlePillars);
+        }
+        for (i, pillar) in puzzle.pillars.iter().enumerate() {
+            if pillar.len() != self.pillar_sz {
+                return Err(InvalidRules::PuzzlePillarSize {
+                    pillar: i + 1,
+                    len: pillar.len(),
+                    pillar_sz: self.pillar_sz,
+                });
+            }
+        }
+        // the blocks have to start below the rows a pillar tops out in
+        Board::from_bottom_rows(self.board_sz, &puzzle.board).map_err(InvalidRules::PuzzleBoard)?;
+        Ok(())
+    }
+
+    /// The puzzle being played, if this is one.
+    pub fn puzzle(&self) -> Option<&Puzzle> {
+        match self.mode {
+            GameMode::Puzzle => self.puzzle.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn grid(&self) -> &dyn Grid {
+        match self.board_shape {
+            BoardShape::Square => &self.topology,
+            BoardShape::Hex => &HexGrid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{load_rules, Block, GameRules, InvalidRules};
+
+    #[test]
+    fn shipped_rules_are_valid() {
+        let rules_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("resources/rules");
+        let mut names = vec![];
+        for entry in std::fs::read_dir(rules_dir).unwrap() {
+            let file = std::fs::File::open(entry.unwrap().path()).unwrap();
+            names.push(load_rules(file).unwrap().name);
+        }
+        assert!(names.contains(&GameRules::default().name));
+        assert!(4 <= names.len());
+    }
+
+    #[test]
+    fn bad_rules_are_explained() {
+        let text = "(pillar_sz: 3, board_sz: (7, 3))";
+        let err = load_rules(text.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InvalidRules>(),
+            Some(&InvalidRules::PillarTooTall {
+                pillar_sz: 3,
+                board_height: 3
+            })
+        );
+        assert!(err
+            .to_string()
+            .contains("smaller than the board height of 3"));
+    }
+
+    #[test]
+    fn palettes_need_distinct_colors() {
+        let garbage = "(initial_palette: (blocks: [Red, Garbage]))";
+        let err = load_rules(garbage.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InvalidRules>(),
+            Some(&InvalidRules::GarbageInPalette)
+        );
+        let repeated = "(initial_palette: (blocks: [Red, Red, Blue]))";
+        let err = load_rules(repeated.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InvalidRules>(),
+            Some(&InvalidRules::RepeatedColor(Block::Red))
+        );
+    }
+}
@@ -1,25 +1,159 @@
-use ggez::graphics::Color;
+use ggez::graphics::{Align, Color};
 use ggez::{event, graphics, GameResult};
+use glam::vec2;
 
-use crate::{assets::Assets, the_rules::GameRules};
 use crate::{
-    drawing::{draw_centered_text, lerp_color, YELLOW},
-    game_loop::GameState,
+    assets::Assets,
+    puzzle::{load_progress, PuzzleProgress, PUZZLE_PROGRESS_PATH},
+    replay::{load_replay, LAST_REPLAY_PATH},
+    saved_game::{has_saved_game, load_game, remove_saved_game, SAVED_GAME_PATH},
+    scoring::{high_score_path, hint_mark, load_high_scores_table, HighScore, SprintTime},
+    simulation::{effects_rng, Simulation},
+    the_rules::{format_clock, GameMode, GameRules, MatchRule},
+};
+use crate::{
+    drawing::{draw_centered_text, lerp_color, RED, YELLOW},
+    game_loop::{present, GameState},
     input::{PlayerCommand, PlayerInput},
+    lan::JoinLan,
+    online::{Connect, Connecting, DEFAULT_PORT},
     the_pillar_descending::ThePillarIsFalling,
+    versus::Versus,
 };
 
-pub struct TitleScreen {}
+pub struct TitleScreen {
+    /// index into the rulesets loaded with the assets
+    pub rule_set: usize,
+    /// replaces the ruleset's match rule if set
+    pub match_rule: Option<MatchRule>,
+    /// index into `GameMode::CHOICES`, then on into the puzzles
+    pub mode: usize,
+    /// the line picked with up and down, the ruleset, the match rule or the mode
+    pub selected_line: usize,
+    /// high scores of the picked mode, unless it is marathon
+    pub mode_scores: Vec<String>,
+    /// digits typed in to replay a seed, a random seed is used if empty
+    pub seed_text: String,
+    /// there is an unfinished game to carry on with
+    pub can_continue: bool,
+    pub puzzle_progress: PuzzleProgress,
+}
 
 impl TitleScreen {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            rule_set: 0,
+            match_rule: None,
+            mode: 0,
+            selected_line: 0,
+            mode_scores: Vec::new(),
+            seed_text: String::new(),
+            can_continue: has_saved_game(SAVED_GAME_PATH),
+            puzzle_progress: load_progress(PUZZLE_PROGRESS_PATH).unwrap_or_default(),
+        }
+    }
+
+    /// Index into the puzzles loaded with the assets, if one is picked.
+    fn puzzle(&self) -> Option<usize> {
+        self.mode.checked_sub(GameMode::CHOICES.len())
+    }
+
+    fn rules(&self, assets: &Assets) -> GameRules {
+        if let Some(puzzle) = self.puzzle() {
+            // puzzles are played by their own rules
+            return assets.puzzles[puzzle].clone();
+        }
+        let rules = assets.rule_sets[self.rule_set].clone();
+        GameRules {
+            match_rule: self.match_rule.unwrap_or(rules.match_rule),
+            mode: GameMode::CHOICES[self.mode],
+            ..rules
+        }
+    }
+
+    /// Versus is played by the picked ruleset until one side tops out.
+    fn versus_rules(&self, assets: &Assets) -> GameRules {
+        let rules = match self.puzzle() {
+            Some(_) => assets.rule_sets[self.rule_set].clone(),
+            None => self.rules(assets),
+        };
+        GameRules {
+            mode: GameMode::Marathon,
+            ..rules
+        }
+    }
+
+    /// Read in the high scores for the mode, marathon's are kept with the assets.
+    fn load_mode_scores(&mut self, assets: &Assets) {
+        let mode = self.rules(assets).mode;
+        let path = high_score_path(mode);
+        self.mode_scores = match mode {
+            GameMode::Marathon | GameMode::Puzzle => Vec::new(),
+            GameMode::TimeAttack { .. } | GameMode::RisingFloor { .. } => {
+                load_high_scores_table::<HighScore, _>(path)
+                    .map(|table| {
+                        table
+                            .list()
+                            .iter()
+                            .take(10)
+                            .map(|h| {
+                                let mark = hint_mark(h.hinted);
+                                format!("{:>2}. {} {:>8}{}", h.level, h.handle, h.score, mark)
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            }
+            GameMode::Sprint { .. } => load_high_scores_table::<SprintTime, _>(path)
+                .map(|table| {
+                    table
+                        .list()
+                        .iter()
+                        .take(10)
+                        .map(|t| {
+                            let time = format_clock(t.millis as f32 / 1000.0);
+                            let mark = hint_mark(t.hinted);
+                            format!("{:>2}. {} {:>8}{}", t.level, t.handle, time, mark)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+    }
+
+    /// The goal of the picked puzzle and how far it has been got with.
+    fn puzzle_lines(&self, rules: &GameRules, assets: &Assets) -> Vec<String> {
+        let puzzle = match rules.puzzle() {
+            Some(puzzle) => puzzle,
+            None => return vec![],
+        };
+        let best = match self.puzzle_progress.best(&rules.name) {
+            Some(best) => format!("solved with {} pillars", best),
+            None => "not solved yet".to_owned(),
+        };
+        vec![
+            puzzle.goal.describe(),
+            format!("with {} pillars", puzzle.pillars.len()),
+            best,
+            String::new(),
+            format!(
+                "{} of {} puzzles solved",
+                self.puzzle_progress.solved_count(),
+                assets.puzzles.len()
+            ),
+        ]
+    }
+
+    fn seed(&self) -> u64 {
+        self.seed_text
+            .parse()
+            .unwrap_or_else(|_| rand::random::<u64>())
     }
 }
 
 impl GameState for TitleScreen {
     fn update(
-        self: Box<Self>,
+        mut self: Box<Self>,
         ctx: &mut ggez::Context,
         assets: &Assets,
         input_state: &crate::input::InputState<PlayerInput, PlayerCommand>,
@@ -28,10 +162,102 @@ impl GameState for TitleScreen {
         if input_state.just_active(PlayerCommand::Quit) {
             event::quit(ctx);
         }
+        if self.puzzle().is_some() {
+            // the ruleset and match rule don't apply to puzzles
+            self.selected_line = 2;
+        } else if input_state.just_active(PlayerCommand::MoveDown) {
+            self.selected_line = (self.selected_line + 1) % 3;
+        } else if input_state.just_active(PlayerCommand::HardDrop) {
+            self.selected_line = (self.selected_line + 2) % 3;
+        }
+        // steps forward, or back by going nearly all the way round
+        let step = |len: usize| {
+            if input_state.just_active(PlayerCommand::MoveLeft) {
+                Some(len - 1)
+            } else if input_state.just_active(PlayerCommand::MoveRight) {
+                Some(1)
+            } else {
+                None
+            }
+        };
+        match self.selected_line {
+            0 => {
+                let len = assets.rule_sets.len();
+                if let Some(step) = step(len) {
+                    self.rule_set = (self.rule_set + step) % len;
+                    self.match_rule = None;
+                }
+            }
+            1 => {
+                if step(2).is_some() {
+                    self.match_rule = Some(self.rules(assets).match_rule.toggle());
+                }
+            }
+            _ => {
+                let len = GameMode::CHOICES.len() + assets.puzzles.len();
+                if let Some(step) = step(len) {
+                    self.mode = (self.mode + step) % len;
+                    self.load_mode_scores(assets);
+                }
+            }
+        }
+        if self.can_continue && input_state.just_active(PlayerCommand::Continue) {
+            match load_game(SAVED_GAME_PATH) {
+                Ok(sim) => {
+                    if let Err(e) = remove_saved_game(SAVED_GAME_PATH) {
+                        eprintln!("couldn't remove the saved game: {}", e);
+                    }
+                    commands.set_score(sim.score);
+                    *assets.effects_rng.borrow_mut() = effects_rng(sim.common.seed);
+                    return Some(present(sim, assets, commands));
+                }
+                Err(e) => {
+                    eprintln!("couldn't load the saved game: {}", e);
+                    self.can_continue = false;
+                }
+            }
+        }
+        if input_state.just_active(PlayerCommand::Replay) {
+            match load_replay(LAST_REPLAY_PATH) {
+                Ok(replay) => {
+                    commands.reset_score();
+                    *assets.effects_rng.borrow_mut() = effects_rng(replay.seed);
+                    // the replay is already saved, watching it doesn't record another
+                    let sim = Simulation::new_game(replay.rules.clone(), replay.seed);
+                    let new_state = ThePillarIsFalling::new(sim);
+                    commands.play_back(replay);
+                    return Some(Box::new(new_state));
+                }
+                Err(e) => eprintln!("couldn't load the replay: {}", e),
+            }
+        }
+        let versus = input_state.just_active(PlayerCommand::Versus);
+        if versus || input_state.just_active(PlayerCommand::VersusBot) {
+            let rules = self.versus_rules(assets);
+            let seed = self.seed();
+            *assets.effects_rng.borrow_mut() = effects_rng(seed);
+            // the bot plays at its middle level until another is picked
+            let bot_level = if versus { None } else { Some(1) };
+            let new_state = Versus::new(ctx, rules, seed, [0, 0], bot_level);
+            return Some(Box::new(new_state));
+        }
+        if input_state.just_active(PlayerCommand::Host) {
+            let connect = Connect::Host {
+                port: DEFAULT_PORT,
+                udp: false,
+            };
+            let rules = self.versus_rules(assets);
+            return Some(Box::new(Connecting::new(connect, rules, self.seed())));
+        }
+        if input_state.just_active(PlayerCommand::JoinLan) {
+            return Some(Box::new(JoinLan::new()));
+        }
         if input_state.just_active(PlayerCommand::Start) {
             commands.reset_score();
-            let new_state =
-                ThePillarIsFalling::new_game(GameRules::default(), &mut *assets.rng.borrow_mut());
+            let rules = self.rules(assets);
+            let seed = self.seed();
+            *assets.effects_rng.borrow_mut() = effects_rng(seed);
+            let new_state = ThePillarIsFalling::new_game(rules, seed);
             return Some(Box::new(new_state));
         }
 
@@ -44,14 +270,26 @@ impl GameState for TitleScreen {
         draw_centered_text(ctx, &title_message, cursor_y, Color::WHITE)?;
 
         cursor_y += 60.0;
-        let scores = assets.high_score_table.borrow();
+        let rules = self.rules(assets);
+        let scores: Vec<String> = if rules.mode == GameMode::Puzzle {
+            self.puzzle_lines(&rules, assets)
+        } else if rules.mode == GameMode::Marathon {
+            let scores = assets.high_score_table.borrow();
+            scores
+                .list()
+                .iter()
+                .take(10)
+                .map(|h| {
+                    let mark = hint_mark(h.hinted);
+                    format!("{:>2}. {} {:>8}{}", h.level, h.handle, h.score, mark)
+                })
+                .collect()
+        } else {
+            self.mode_scores.clone()
+        };
 
-        for score in scores.list().iter().take(10) {
-            let score_text = graphics::Text::new((
-                format!("{:>2}. {} {:>8}", score.level, score.handle, score.score),
-                assets.font,
-                25.0,
-            ));
+        for score in scores {
+            let score_text = graphics::Text::new((score, assets.font, 25.0));
 
             draw_centered_text(ctx, &score_text, cursor_y, Color::WHITE)?;
             cursor_y += 20.0;
@@ -59,6 +297,53 @@ impl GameState for TitleScreen {
 
         let begin_text = graphics::Text::new(("press start", assets.font, 30.0));
         let y_begin = graphics::screen_coordinates(ctx).bottom() - 60.0;
+        let lines = if rules.mode == GameMode::Puzzle {
+            [
+                String::new(),
+                String::new(),
+                format!("< puzzle {} >", rules.name),
+            ]
+        } else {
+            [
+                format!("< rules {} >", rules.name),
+                format!("< match {} >", rules.match_rule.name()),
+                format!("< mode {} >", rules.mode.name()),
+            ]
+        };
+        for (i, line) in lines.iter().enumerate() {
+            let color = if i == self.selected_line {
+                YELLOW
+            } else {
+                Color::WHITE
+            };
+            let text = graphics::Text::new((line.as_str(), assets.font, 25.0));
+            draw_centered_text(ctx, &text, y_begin - 175.0 + i as f32 * 30.0, color)?;
+        }
+        let seed_text = if self.seed_text.is_empty() {
+            "seed random".to_owned()
+        } else {
+            format!("seed {}", self.seed_text)
+        };
+        let seed_text = graphics::Text::new((seed_text, assets.font, 18.0));
+        draw_centered_text(ctx, &seed_text, y_begin - 70.0, Color::WHITE)?;
+        if self.can_continue {
+            let text = graphics::Text::new(("enter to continue", assets.font, 18.0));
+            draw_centered_text(ctx, &text, y_begin - 45.0, Color::WHITE)?;
+        }
+        let hint = "v versus  b bot  h host  j join LAN";
+        let hint_text = graphics::Text::new((hint, assets.font, 18.0));
+        draw_centered_text(ctx, &hint_text, y_begin + 35.0, Color::WHITE)?;
+
+        // rulesets that wouldn't load, with the reason
+        let width = graphics::screen_coordinates(ctx).w - 20.0;
+        let mut y = cursor_y + 20.0;
+        for error in assets.rule_errors.iter() {
+            let mut text = graphics::Text::new((error.as_str(), assets.font, 12.0));
+            text.set_bounds(vec2(width, f32::INFINITY), Align::Center);
+            draw_centered_text(ctx, &text, y, RED)?;
+            y += text.height(ctx) + 4.0;
+        }
+
         let begin_color = lerp_color(
             Color::WHITE,
             YELLOW,
@@ -68,4 +353,16 @@ impl GameState for TitleScreen {
 
         Ok(())
     }
+
+    fn text_input(&mut self, character: char) {
+        // u64 seeds have at most 20 digits
+        if character.is_ascii_digit() && self.seed_text.len() < 20 {
+            self.seed_text.push(character);
+            if self.seed_text.parse::<u64>().is_err() {
+                self.seed_text.pop();
+            }
+        } else if character == '\u{8}' {
+            self.seed_text.pop();
+        }
+    }
 }
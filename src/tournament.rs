@@ -0,0 +1,344 @@
+use std::{
+    fmt::Write,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    bot::{Bot, BotSettings},
+    input::InputState,
+    simulation::{Ending, Phase, SimEvent, Simulation},
+    the_rules::GameRules,
+};
+
+/// Every game is played in steps of this many seconds, like online games.
+const STEP: f32 = 1.0 / 60.0;
+
+/// Chains are counted by length up to this many clears, longer ones go in the last column.
+pub const CHAIN_COLUMNS: usize = 5;
+
+pub const USAGE: &str = "usage: pillars-sim RULES.ron... [--games N] [--seed FIRST] \
+[--threads N] [--minutes M] [--skill 0-1] [--press-every SECONDS] [--csv]";
+
+/// Bot games to play for each ruleset, from the command line of `pillars-sim`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tournament {
+    pub rules_paths: Vec<PathBuf>,
+    /// games per ruleset, the same seeds for each
+    pub games: u64,
+    pub first_seed: u64,
+    pub threads: usize,
+    /// seconds of play before a game that hasn't topped out is stopped
+    pub time_limit: f32,
+    pub bot: BotSettings,
+    pub csv: bool,
+}
+
+impl Default for Tournament {
+    fn default() -> Self {
+        Self {
+            rules_paths: vec![],
+            games: 100,
+            first_seed: 0,
+            threads: thread::available_parallelism().map_or(4, |n| n.get()),
+            time_limit: 600.0,
+            bot: BotSettings::default(),
+            csv: false,
+        }
+    }
+}
+
+/// Read the rulesets to play and how from the command line.
+pub fn parse_args<I: Iterator<Item = String>>(args: I) -> Result<Tournament, String> {
+    let mut tournament = Tournament::default();
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            tournament.rules_paths.push(arg.into());
+            continue;
+        }
+        if arg == "--csv" {
+            tournament.csv = true;
+            continue;
+        }
+        let value = args.next().ok_or_else(|| USAGE.to_owned())?;
+        match arg.as_str() {
+            "--games" => tournament.games = parse_value(&arg, &value)?,
+            "--seed" => tournament.first_seed = parse_value(&arg, &value)?,
+            "--threads" => tournament.threads = parse_value(&arg, &value)?,
+            "--minutes" => tournament.time_limit = 60.0 * parse_value::<f32>(&arg, &value)?,
+            "--skill" => tournament.bot.skill = parse_value(&arg, &value)?,
+            "--press-every" => tournament.bot.press_every = parse_value(&arg, &value)?,
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    if tournament.rules_paths.is_empty() || tournament.games == 0 || tournament.threads == 0 {
+        return Err(USAGE.to_owned());
+    }
+    Ok(tournament)
+}
+
+fn parse_value<T: FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("bad value {} for {}\n{}", value, arg, USAGE))
+}
+
+/// How one game went.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GameStats {
+    pub seed: u64,
+    /// seconds until the game ended, or the time limit
+    pub survived: f32,
+    pub topped_out: bool,
+    pub score: u64,
+    pub level: u64,
+    pub drops: u64,
+    /// chains made of each length, from a single clear up
+    pub chains: [u64; CHAIN_COLUMNS],
+}
+
+/// The bot plays a game by `rules` dealt from `seed`, until it is over or time runs out.
+pub fn play_game(
+    rules: &GameRules,
+    seed: u64,
+    settings: BotSettings,
+    time_limit: f32,
+) -> GameStats {
+    let mut sim = Simulation::new_game(rules.clone(), seed);
+    let mut bot = Bot::new(settings, seed);
+    let mut input_state = InputState::<(), _>::new(Default::default());
+    let mut chains = [0; CHAIN_COLUMNS];
+    while !sim.is_game_over() && sim.common.clock < time_limit {
+        bot.update(&sim, STEP, &mut input_state);
+        for event in sim.update(STEP, &input_state) {
+            if let SimEvent::ChainFinished { chain, .. } = event {
+                chains[(chain as usize).clamp(1, CHAIN_COLUMNS) - 1] += 1;
+            }
+        }
+        input_state.save_current();
+    }
+    GameStats {
+        seed,
+        survived: sim.common.clock,
+        topped_out: matches!(
+            sim.phase,
+            Phase::GameOver {
+                ending: Ending::ToppedOut
+            }
+        ),
+        score: sim.score,
+        level: sim.common.level,
+        drops: sim.common.drop_count,
+        chains,
+    }
+}
+
+/// The games played by one ruleset, summed up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    pub name: String,
+    pub games: u64,
+    pub topped_out: u64,
+    pub mean_survived: f64,
+    pub mean_score: f64,
+    pub mean_level: f64,
+    pub max_level: u64,
+    pub mean_drops: f64,
+    pub chains: [u64; CHAIN_COLUMNS],
+}
+
+impl Summary {
+    pub fn new(name: &str, stats: &[GameStats]) -> Self {
+        let games = stats.len() as u64;
+        let mean = |value: &dyn Fn(&GameStats) -> f64| {
+            stats.iter().map(value).sum::<f64>() / games.max(1) as f64
+        };
+        let mut chains = [0; CHAIN_COLUMNS];
+        for game in stats {
+            for (total, count) in chains.iter_mut().zip(game.chains) {
+                *total += count;
+            }
+        }
+        Self {
+            name: name.to_owned(),
+            games,
+            topped_out: stats.iter().filter(|game| game.topped_out).count() as u64,
+            mean_survived: mean(&|game| game.survived as f64),
+            mean_score: mean(&|game| game.score as f64),
+            mean_level: mean(&|game| game.level as f64),
+            max_level: stats.iter().map(|game| game.level).max().unwrap_or(0),
+            mean_drops: mean(&|game| game.drops as f64),
+            chains,
+        }
+    }
+
+    /// The share of chains of each length, in percent.
+    fn chain_shares(&self) -> Vec<f64> {
+        let total = self.chains.iter().sum::<u64>().max(1) as f64;
+        self.chains
+            .iter()
+            .map(|&count| 100.0 * count as f64 / total)
+            .collect()
+    }
+}
+
+/// Play every ruleset's games spread over the tournament's threads.
+pub fn run(tournament: &Tournament, rule_sets: &[GameRules]) -> Vec<Summary> {
+    let jobs: Vec<(usize, u64)> = (0..rule_sets.len())
+        .flat_map(|r| (0..tournament.games).map(move |game| (r, tournament.first_seed + game)))
+        .collect();
+    let next_job = AtomicUsize::new(0);
+    let results = Mutex::new(vec![vec![]; rule_sets.len()]);
+    thread::scope(|scope| {
+        for _ in 0..tournament.threads {
+            scope.spawn(|| {
+                while let Some(&(r, seed)) = jobs.get(next_job.fetch_add(1, Ordering::Relaxed)) {
+                    let stats =
+                        play_game(&rule_sets[r], seed, tournament.bot, tournament.time_limit);
+                    results.lock().unwrap()[r].push(stats);
+                }
+            });
+        }
+    });
+    let results = results.into_inner().unwrap();
+    rule_sets
+        .iter()
+        .zip(results)
+        .map(|(rules, mut stats)| {
+            // finished in any order, but listed by seed
+            stats.sort_by_key(|game| game.seed);
+            Summary::new(&rules.name, &stats)
+        })
+        .collect()
+}
+
+fn chain_headings() -> Vec<String> {
+    (1..=CHAIN_COLUMNS)
+        .map(|length| match length {
+            CHAIN_COLUMNS => format!("chain {}+", length),
+            _ => format!("chain {}", length),
+        })
+        .collect()
+}
+
+/// A table to read, with chains as a share of all chains made.
+pub fn table(summaries: &[Summary]) -> String {
+    let mut out = format!(
+        "{:<12} {:>6} {:>8} {:>10} {:>10} {:>7} {:>5} {:>7}",
+        "rules", "games", "topped", "survived s", "score", "level", "max", "drops"
+    );
+    for heading in chain_headings() {
+        write!(out, " {:>8}", heading).unwrap();
+    }
+    out.push('\n');
+    for s in summaries {
+        write!(
+            out,
+            "{:<12} {:>6} {:>7.0}% {:>10.1} {:>10.0} {:>7.2} {:>5} {:>7.1}",
+            s.name,
+            s.games,
+            100.0 * s.topped_out as f64 / s.games.max(1) as f64,
+            s.mean_survived,
+            s.mean_score,
+            s.mean_level,
+            s.max_level,
+            s.mean_drops,
+        )
+        .unwrap();
+        for share in s.chain_shares() {
+            write!(out, " {:>7.1}%", share).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// One row per ruleset for a spreadsheet, with chains as counts.
+pub fn csv(summaries: &[Summary]) -> String {
+    let mut out = "rules,games,topped_out,mean_survived,mean_score,mean_level,max_level,mean_drops"
+        .to_owned();
+    for length in 1..=CHAIN_COLUMNS {
+        write!(out, ",chain_{}", length).unwrap();
+    }
+    out.push_str("_or_more\n");
+    for s in summaries {
+        write!(
+            out,
+            "{},{},{},{:.2},{:.2},{:.3},{},{:.2}",
+            s.name.replace(',', " "),
+            s.games,
+            s.topped_out,
+            s.mean_survived,
+            s.mean_score,
+            s.mean_level,
+            s.max_level,
+            s.mean_drops,
+        )
+        .unwrap();
+        for count in s.chains {
+            write!(out, ",{}", count).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv, parse_args, play_game, run, Summary, Tournament};
+    use crate::bot::BotSettings;
+    use crate::the_rules::GameRules;
+
+    #[test]
+    fn command_line_picks_the_games() {
+        let args = |line: &str| parse_args(line.split_whitespace().map(str::to_owned));
+        let tournament = args("easy.ron hard.ron --games 10 --seed 5 --minutes 2 --csv").unwrap();
+        assert_eq!(tournament.rules_paths.len(), 2);
+        assert_eq!(tournament.games, 10);
+        assert_eq!(tournament.first_seed, 5);
+        assert_eq!(tournament.time_limit, 120.0);
+        assert!(tournament.csv);
+        assert!(args("--games 10").is_err());
+        assert!(args("easy.ron --games ten").is_err());
+        assert!(args("easy.ron --skill").is_err());
+    }
+
+    #[test]
+    fn games_are_the_same_on_any_number_of_threads() {
+        let rule_sets = [GameRules::default()];
+        let tournament = Tournament {
+            games: 3,
+            time_limit: 20.0,
+            bot: BotSettings {
+                press_every: 0.05,
+                skill: 0.3,
+            },
+            ..Default::default()
+        };
+        let threads = |threads| Tournament {
+            threads,
+            ..tournament.clone()
+        };
+        let one = run(&threads(1), &rule_sets);
+        assert_eq!(one, run(&threads(3), &rule_sets));
+        let game = play_game(&rule_sets[0], 0, BotSettings::default(), 20.0);
+        assert!(0 < game.drops);
+        assert_eq!(one[0].games, 3);
+    }
+
+    #[test]
+    fn csv_has_a_column_for_each_heading() {
+        let summary = Summary::new("classic", &[]);
+        let csv = csv(&[summary]);
+        let mut lines = csv.lines();
+        let headings = lines.next().unwrap().split(',').count();
+        assert_eq!(lines.next().unwrap().split(',').count(), headings);
+        assert!(csv.starts_with("rules,games,"));
+    }
+}
@@ -0,0 +1,415 @@
+use std::{collections::HashMap, hash::Hash};
+
+use ggez::{
+    graphics::{self, Color, DrawMode, MeshBuilder, Rect},
+    Context, GameResult,
+};
+use glam::{vec2, Vec2};
+
+use crate::input::*;
+use crate::{
+    assets::Assets,
+    bot::{Bot, BOT_LEVELS},
+    drawing::{
+        draw_centered_text, draw_game_play, draw_ghost_pillar, lerp_color, RED, WINDOW_HEIGHT,
+        WINDOW_WIDTH, YELLOW,
+    },
+    game_loop::{draw_scores, present_events, wants_pause, Commands, GameState},
+    helpful_things::time_delta,
+    simulation::{effects_rng, Phase, SimEvent, Simulation},
+    the_rules::GameRules,
+    title_screen::TitleScreen,
+};
+
+/// Rows of garbage a chain sends to the other board:
+/// one for each clear after the first, and one more for every ten blocks.
+pub fn garbage_for(chain: u64, blocks: u64) -> u64 {
+    chain.saturating_sub(1) + blocks / 10
+}
+
+/// Send the garbage from the chains each side finished this frame over to the other side.
+pub fn exchange_garbage(sims: &mut [Simulation; 2], events: &[Vec<SimEvent>; 2]) {
+    for (player, events) in events.iter().enumerate() {
+        for event in events {
+            if let SimEvent::ChainFinished { chain, blocks } = *event {
+                sims[1 - player].send_garbage(garbage_for(chain, blocks));
+            }
+        }
+    }
+}
+
+/// Advance both boards by a frame, each with its own side's input.
+pub fn step_both<I>(
+    sims: &mut [Simulation; 2],
+    inputs: [&InputState<I, PlayerCommand>; 2],
+    time_delta: f32,
+) -> [Vec<SimEvent>; 2]
+where
+    I: Copy + Eq + Hash,
+{
+    let events = [
+        sims[0].update(time_delta, inputs[0]),
+        sims[1].update(time_delta, inputs[1]),
+    ];
+    exchange_garbage(sims, &events);
+    events
+}
+
+/// How a versus match ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    Won(usize),
+    /// both boards topped out on the same frame
+    Draw,
+}
+
+/// The outcome once either board has topped out.
+pub fn outcome(sims: &[Simulation; 2]) -> Option<Outcome> {
+    match [sims[0].is_game_over(), sims[1].is_game_over()] {
+        [true, true] => Some(Outcome::Draw),
+        [true, false] => Some(Outcome::Won(1)),
+        [false, true] => Some(Outcome::Won(0)),
+        [false, false] => None,
+    }
+}
+
+/// Two boards side by side, each player sending garbage to the other, until one tops out.
+pub struct Versus {
+    pub sims: [Simulation; 2],
+    /// matches won by each side since versus was picked
+    pub wins: [u64; 2],
+    /// `None` while the match is still being played
+    pub outcome: Option<Outcome>,
+    paused: bool,
+    fade: f32,
+    /// the bot playing the right board, with its index into `BOT_LEVELS`
+    bot: Option<(usize, Bot)>,
+    /// the commands the bot presses
+    bot_input: InputState<PlayerInput, PlayerCommand>,
+}
+
+impl Versus {
+    /// Both sides start from the same seed, so they are dealt the same pillars.
+    /// With a bot level the right board is played by the bot.
+    pub fn new(
+        ctx: &mut Context,
+        rules: GameRules,
+        seed: u64,
+        wins: [u64; 2],
+        bot_level: Option<usize>,
+    ) -> Self {
+        set_window_width(ctx, 2.0 * WINDOW_WIDTH);
+        let bot = bot_level.map(|level| (level, Bot::new(BOT_LEVELS[level].1, seed)));
+        Self {
+            sims: [
+                Simulation::new_game(rules.clone(), seed),
+                Simulation::new_game(rules, seed),
+            ],
+            wins,
+            outcome: None,
+            paused: false,
+            fade: 0.0,
+            bot,
+            bot_input: InputState::new(HashMap::new()),
+        }
+    }
+
+    fn rematch(self, ctx: &mut Context, assets: &Assets) -> Self {
+        let seed = rand::random::<u64>();
+        *assets.effects_rng.borrow_mut() = effects_rng(seed);
+        let rules = self.sims[0].common.rules.clone();
+        let bot_level = self.bot.as_ref().map(|&(level, _)| level);
+        Self::new(ctx, rules, seed, self.wins, bot_level)
+    }
+
+    /// Left and right pick how well the bot plays, between matches or while paused.
+    fn pick_bot_level(&mut self, input_state: &InputState<PlayerInput, PlayerCommand>) {
+        if let Some((level, bot)) = &mut self.bot {
+            let len = BOT_LEVELS.len();
+            if input_state.just_active(PlayerCommand::MoveLeft) {
+                *level = (*level + len - 1) % len;
+            } else if input_state.just_active(PlayerCommand::MoveRight) {
+                *level = (*level + 1) % len;
+            }
+            bot.settings = BOT_LEVELS[*level].1;
+        }
+    }
+
+    fn headline(&self) -> &'static str {
+        if self.bot.is_some() {
+            match self.outcome {
+                Some(Outcome::Won(0)) => return "YOU WIN",
+                Some(Outcome::Won(_)) => return "BOT WINS",
+                _ => {}
+            }
+        }
+        match self.outcome {
+            Some(Outcome::Won(0)) => "LEFT WINS",
+            Some(Outcome::Won(_)) => "RIGHT WINS",
+            Some(Outcome::Draw) => "DRAW",
+            None => "PAUSED",
+        }
+    }
+}
+
+/// How far a side's board sits from the middle of the window.
+pub fn board_offset(player: usize) -> Vec2 {
+    vec2((player as f32 - 0.5) * WINDOW_WIDTH, 0.0)
+}
+
+/// Versus needs room for two boards, everything else for one.
+pub fn set_window_width(ctx: &mut Context, width: f32) {
+    let resized = graphics::set_drawable_size(ctx, width, WINDOW_HEIGHT).and_then(|()| {
+        graphics::set_screen_coordinates(ctx, Rect::new(0.0, 0.0, width, WINDOW_HEIGHT))
+    });
+    if let Err(e) = resized {
+        eprintln!("couldn't resize the window: {}", e);
+    }
+}
+
+pub fn back_to_title(ctx: &mut Context) -> Box<dyn GameState> {
+    set_window_width(ctx, WINDOW_WIDTH);
+    Box::new(TitleScreen::new())
+}
+
+/// One side's board, drawn the way the single player states draw each phase.
+pub fn draw_simulation(
+    ctx: &mut Context,
+    assets: &Assets,
+    sim: &Simulation,
+    offset: Vec2,
+) -> GameResult {
+    match &sim.phase {
+        Phase::Falling | Phase::Landed { .. } => {
+            let pillar = Some((&sim.current_pillar, sim.pillar_pos.x, sim.pillar_pos.y));
+            draw_game_play(ctx, assets, &sim.common, pillar, None, offset)?;
+            if let Some((pos, marked)) = sim.ghost() {
+                let pillar = &sim.current_pillar;
+                draw_ghost_pillar(ctx, assets, &sim.common, pillar, pos, &marked, offset)?;
+            }
+            Ok(())
+        }
+        Phase::Matching {
+            h_s,
+            current_matches,
+            ..
+        } => {
+            let ms: Vec<_> = current_matches.iter().map(|&(a, _)| a).collect();
+            let highlights = Some((ms.as_slice(), *h_s));
+            draw_game_play(ctx, assets, &sim.common, None, highlights, offset)
+        }
+        _ => draw_game_play(ctx, assets, &sim.common, None, None, offset),
+    }
+}
+
+/// Both boards, the garbage on its way to each and their scores in the corners.
+pub fn draw_boards(ctx: &mut Context, assets: &Assets, sims: &[Simulation; 2]) -> GameResult {
+    let rect = graphics::screen_coordinates(ctx);
+    for (player, sim) in sims.iter().enumerate() {
+        draw_simulation(ctx, assets, sim, board_offset(player))?;
+        if 0 < sim.incoming_garbage {
+            let text = format!("+{} rows", sim.incoming_garbage);
+            let text = graphics::Text::new((text, assets.font, 18.0));
+            let x = if player == 0 {
+                20.0
+            } else {
+                rect.right() - 20.0 - text.width(ctx)
+            };
+            graphics::draw(ctx, &text, (vec2(x, 50.0), RED))?;
+        }
+    }
+    draw_scores(ctx, assets, sims[0].score, Some(sims[1].score))
+}
+
+/// Darken the boards and put a headline over them, flashing if the match is over.
+pub fn draw_banner(
+    ctx: &mut Context,
+    assets: &Assets,
+    headline: &str,
+    lines: &[String],
+    fade: f32,
+    flash: bool,
+) -> GameResult {
+    let rect = graphics::screen_coordinates(ctx);
+    let mesh = MeshBuilder::new()
+        .rectangle(DrawMode::fill(), rect, Color::new(0., 0., 0., fade))?
+        .build(ctx)?;
+    graphics::draw(ctx, &mesh, (Vec2::ZERO,))?;
+
+    let y = rect.center().y - 100.0;
+    let t = ggez::timer::time_since_start(ctx).as_secs_f32();
+    let headline = graphics::Text::new((headline, assets.font, 36.0));
+    let color = if flash {
+        lerp_color(Color::WHITE, YELLOW, t.sin().abs())
+    } else {
+        Color::WHITE
+    };
+    draw_centered_text(ctx, &headline, y, color)?;
+    let mut y = y + 60.0;
+    for line in lines {
+        let text = graphics::Text::new((line.as_str(), assets.font, 25.0));
+        draw_centered_text(ctx, &text, y, Color::WHITE)?;
+        y += 35.0;
+    }
+    Ok(())
+}
+
+impl GameState for Versus {
+    fn update(
+        mut self: Box<Self>,
+        ctx: &mut Context,
+        assets: &Assets,
+        input_state: &InputState<PlayerInput, PlayerCommand>,
+        commands: &mut Commands,
+    ) -> Option<Box<dyn GameState>> {
+        if self.outcome.is_some() || self.paused {
+            self.pick_bot_level(input_state);
+        }
+        if self.outcome.is_some() {
+            self.fade = (self.fade + 0.5 * time_delta(ctx)).min(0.9);
+            if input_state.just_active(PlayerCommand::Start) {
+                return Some(Box::new(self.rematch(ctx, assets)));
+            }
+            if input_state.just_active(PlayerCommand::Continue) {
+                return Some(back_to_title(ctx));
+            }
+            return Some(self);
+        }
+        if self.paused {
+            if input_state.just_active(PlayerCommand::Pause)
+                || input_state.just_active(PlayerCommand::Start)
+            {
+                self.paused = false;
+            } else if input_state.just_active(PlayerCommand::Continue) {
+                return Some(back_to_title(ctx));
+            }
+            return Some(self);
+        }
+        if wants_pause(input_state, commands) {
+            self.paused = true;
+            return Some(self);
+        }
+
+        let time_step = commands.time_step();
+        let inputs = match &mut self.bot {
+            Some((_, bot)) => {
+                // one player against the bot has the whole keyboard
+                bot.update(&self.sims[1], time_step, &mut self.bot_input);
+                [input_state, &self.bot_input]
+            }
+            None => [commands.versus_input(0), commands.versus_input(1)],
+        };
+        let events = step_both(&mut self.sims, inputs, time_step);
+        self.bot_input.save_current();
+        for (player, events) in events.iter().enumerate() {
+            let sim = &self.sims[player];
+            present_events(sim, events, board_offset(player), ctx, assets, commands);
+        }
+        self.outcome = outcome(&self.sims);
+        if let Some(Outcome::Won(player)) = self.outcome {
+            self.wins[player] += 1;
+        }
+        Some(self)
+    }
+
+    fn draw(&self, ctx: &mut Context, assets: &Assets) -> GameResult {
+        draw_boards(ctx, assets, &self.sims)?;
+        if self.outcome.is_none() && !self.paused {
+            return Ok(());
+        }
+        let [left, right] = self.wins;
+        let next = if self.paused {
+            "start to carry on"
+        } else {
+            "start for a rematch"
+        };
+        let mut lines = vec![format!("{} - {}", left, right)];
+        if let Some((level, _)) = self.bot {
+            lines.push(format!("< bot {} >", BOT_LEVELS[level].0));
+        }
+        lines.push(next.to_owned());
+        lines.push("enter for the title screen".to_owned());
+        let fade = if self.paused { 0.7 } else { self.fade };
+        draw_banner(ctx, assets, self.headline(), &lines, fade, !self.paused)
+    }
+
+    fn in_game(&self) -> bool {
+        true
+    }
+
+    fn draws_scores(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exchange_garbage, garbage_for};
+    use crate::board::{Block::*, Board};
+    use crate::input::{default_input_cfg, InputState, PlayerCommand};
+    use crate::pillar::Pillar;
+    use crate::simulation::{SimEvent, Simulation};
+    use crate::the_rules::GameRules;
+
+    #[test]
+    fn a_chain_sends_garbage_to_the_other_board() {
+        let mut sims = [
+            Simulation::new_game(GameRules::default(), 7),
+            Simulation::new_game(GameRules::default(), 7),
+        ];
+        let sz = sims[0].common.board.sz();
+        sims[0].common.board = Board::from_bottom_rows(sz, "BB.....\nRR.GG..").unwrap();
+        sims[0].current_pillar = Pillar::Blocks(vec![Blue, Green, Red]);
+        sims[0].pillar_pos.x = 2;
+        let mut inputs = [
+            InputState::new(default_input_cfg()),
+            InputState::new(default_input_cfg()),
+        ];
+        inputs[0].activate_command(PlayerCommand::HardDrop);
+        let mut chains = vec![];
+        for _ in 0..600 {
+            let events = [
+                sims[0].update(1.0 / 60.0, &inputs[0]),
+                sims[1].update(1.0 / 60.0, &inputs[1]),
+            ];
+            exchange_garbage(&mut sims, &events);
+            chains.extend(
+                events[0]
+                    .iter()
+                    .filter(|&e| matches!(e, SimEvent::ChainFinished { .. }))
+                    .cloned(),
+            );
+            inputs[0].save_current();
+            if 0 < sims[1].incoming_garbage {
+                break;
+            }
+        }
+        assert_eq!(
+            chains,
+            vec![SimEvent::ChainFinished {
+                chain: 3,
+                blocks: 9
+            }]
+        );
+        assert_eq!(sims[1].incoming_garbage, garbage_for(3, 9));
+        assert_eq!(sims[0].incoming_garbage, 0);
+
+        // it comes up under the other board once its pillar is down
+        inputs[1].activate_command(PlayerCommand::HardDrop);
+        for _ in 0..600 {
+            sims[1].update(1.0 / 60.0, &inputs[1]);
+            inputs[1].save_current();
+            if sims[1].incoming_garbage == 0 {
+                break;
+            }
+        }
+        let bottom = sims[1].common.board.y_len() - 1;
+        let width = sims[1].common.board.x_len();
+        for y in [bottom, bottom - 1] {
+            let garbage = (0..width)
+                .filter(|&x| sims[1].common.board[[x, y]] == Some(Garbage))
+                .count();
+            assert_eq!(garbage, width - 1);
+        }
+    }
+}